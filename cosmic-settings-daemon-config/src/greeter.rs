@@ -8,7 +8,7 @@ use std::path::PathBuf;
 pub const GREETER_STATE: Option<&'static str> = option_env!("GREETER_STATE");
 
 /// State applied in the greeter during login, that should be applied in the user config
-#[derive(Default, Debug, Deserialize, Serialize, Clone, CosmicConfigEntry)]
+#[derive(Default, Debug, Deserialize, Serialize, Clone, PartialEq, CosmicConfigEntry)]
 #[version = 1]
 #[serde(deny_unknown_fields)]
 pub struct GreeterAccessibilityState {
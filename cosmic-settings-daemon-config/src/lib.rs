@@ -13,6 +13,70 @@ pub const NAME: &str = "com.system76.CosmicSettingsDaemon";
 #[serde(deny_unknown_fields)]
 pub struct CosmicSettingsDaemonConfig {
     pub mono_sound: bool,
+    /// Theme settings forced on individual Flatpak applications
+    pub flatpak_theme_overrides: Vec<FlatpakThemeOverride>,
+    /// Coordinates used for sunrise and sunset times in place of the timezone's location
+    pub manual_location: Option<ManualLocation>,
+    /// Geonames ID of a city from the installed city database, used for sunrise and sunset
+    /// times when no manual location is set
+    pub location_city: Option<u32>,
+    /// Ask GeoClue for the location when no manual location or city is set
+    pub automatic_location: bool,
+    /// How far the sun must set before the theme switches to dark
+    pub twilight: Twilight,
+    /// Whether the keyboard layout is remembered separately for each window or application
+    pub input_source_memory: InputSourceMemory,
+    /// Which keyboard layout wins when COSMIC and the system's (localed) layouts disagree at login
+    pub keyboard_layout_source: KeyboardLayoutSource,
+}
+
+/// Side whose keyboard layout is kept when COSMIC and the system disagree
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KeyboardLayoutSource {
+    /// COSMIC's layout, if one is set, replaces the system layout and console keymap
+    #[default]
+    Cosmic,
+    /// The system layout, if one is set, replaces COSMIC's layout
+    System,
+}
+
+/// What the active keyboard layout is remembered for
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum InputSourceMemory {
+    /// One layout is shared by all windows
+    #[default]
+    Global,
+    /// Each window keeps the layout it last used
+    Window,
+    /// Each application keeps the layout it last used
+    Application,
+}
+
+/// The end of the evening, and start of the morning, for the automatic theme switch
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Twilight {
+    /// Switch at sunset and sunrise
+    #[default]
+    Sunset,
+    /// Switch when the sun is 6 degrees below the horizon
+    Civil,
+    /// Switch when the sun is 12 degrees below the horizon
+    Nautical,
+}
+
+/// Coordinates of a location chosen by the user
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ManualLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl ManualLocation {
+    /// Whether the latitude is within ±90° and the longitude within ±180°
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
 }
 
 /// Config structure for settings managed by the daemon
@@ -22,6 +86,50 @@ pub struct CosmicSettingsDaemonConfig {
 pub struct CosmicSettingsDaemonState {
     /// the sink that the virtual mono sink is attached to
     pub default_sink_name: String,
+    /// Theme overrides which the daemon last wrote for each Flatpak app
+    pub flatpak_theme_overrides_applied: Vec<AppliedFlatpakThemeOverride>,
+}
+
+/// Theme settings to force on a single Flatpak application
+///
+/// Only GTK 3 and plain GTK 4 apps can be forced light or dark. Libadwaita apps always follow
+/// the system color scheme, since libadwaita has no supported way to override it per app.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FlatpakThemeOverride {
+    /// The Flatpak application ID, such as `org.gnome.Calculator`
+    pub app_id: String,
+    /// Force the light or dark variant instead of following the theme mode
+    ///
+    /// Sets `gtk-application-prefer-dark-theme` in the app's GTK settings.ini, along with the
+    /// matching variant of the GTK theme. This has no effect on libadwaita apps.
+    pub variant: Option<ThemeVariant>,
+    /// GTK theme to use in place of the system GTK theme
+    pub gtk_theme: Option<String>,
+    /// Icon theme to use in place of the system icon theme
+    ///
+    /// Sets `gtk-icon-theme-name` in the app's GTK settings.ini, under
+    /// `~/.var/app/<app_id>/config`. Other settings in the file are left as they are.
+    pub icon_theme: Option<String>,
+}
+
+/// Theme settings which the daemon wrote for a Flatpak application
+#[derive(Default, Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AppliedFlatpakThemeOverride {
+    pub app_id: String,
+    /// The app's `GTK_THEME` environment variable
+    pub gtk_theme: Option<String>,
+    /// `gtk-icon-theme-name` in the app's GTK settings.ini
+    pub icon_theme: Option<String>,
+    /// `gtk-application-prefer-dark-theme` in the app's GTK settings.ini
+    pub prefer_dark: Option<bool>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ThemeVariant {
+    Light,
+    Dark,
 }
 
 impl CosmicSettingsDaemonConfig {
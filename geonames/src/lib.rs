@@ -1,7 +1,294 @@
 pub use bitcode;
 
-#[derive(Clone, Debug, bitcode::Decode, bitcode::Encode)]
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq, bitcode::Decode, bitcode::Encode)]
 pub struct GeoPosition {
     pub latitude: f64,
     pub longitude: f64,
 }
+
+/// Decodes the position of the most populated city in each timezone.
+pub fn decode_timezone_positions(
+    data: &[u8],
+) -> Result<BTreeMap<String, GeoPosition>, bitcode::Error> {
+    bitcode::decode(data)
+}
+
+pub fn encode_timezone_positions(positions: &BTreeMap<String, GeoPosition>) -> Vec<u8> {
+    bitcode::encode(positions)
+}
+
+/// Version of the city database schema, stored ahead of the encoded cities.
+///
+/// Bitcode is not self-describing, so this must be incremented whenever `City` changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A populated place from the geonames database.
+#[derive(Clone, Debug, PartialEq, bitcode::Decode, bitcode::Encode)]
+pub struct City {
+    /// The geonames ID of the city
+    pub id: u32,
+    /// Name of the city, in its local language
+    pub name: String,
+    /// Name of the city in plain ASCII
+    pub ascii_name: String,
+    /// ISO 3166-1 alpha-2 country code
+    pub country_code: String,
+    /// Name of the first-level administrative region, or its code if the name is unknown
+    pub admin1: String,
+    pub population: u64,
+    /// IANA timezone name, such as `America/Denver`
+    pub timezone: String,
+    pub position: GeoPosition,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data was encoded with a different schema version.
+    Version {
+        found: u32,
+        expected: u32,
+    },
+    /// The data is too short to contain a schema version.
+    Truncated,
+    Decode(bitcode::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Version { found, expected } => {
+                write!(f, "geodata schema version {found} is not {expected}")
+            }
+            Error::Truncated => f.write_str("geodata is missing its schema version"),
+            Error::Decode(why) => write!(f, "failed to decode geodata: {why}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Cities sorted from the most to the least populated.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Database {
+    cities: Vec<City>,
+}
+
+impl Database {
+    pub fn new(mut cities: Vec<City>) -> Self {
+        cities.sort_by(|a, b| b.population.cmp(&a.population).then(a.id.cmp(&b.id)));
+        Self { cities }
+    }
+
+    pub fn cities(&self) -> &[City] {
+        &self.cities
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        let (version, cities) = data.split_first_chunk::<4>().ok_or(Error::Truncated)?;
+        let version = u32::from_le_bytes(*version);
+        if version != SCHEMA_VERSION {
+            return Err(Error::Version {
+                found: version,
+                expected: SCHEMA_VERSION,
+            });
+        }
+
+        bitcode::decode(cities)
+            .map(Self::new)
+            .map_err(Error::Decode)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = SCHEMA_VERSION.to_le_bytes().to_vec();
+        data.extend_from_slice(&bitcode::encode(&self.cities));
+        data
+    }
+
+    pub fn get(&self, id: u32) -> Option<&City> {
+        self.cities.iter().find(|city| city.id == id)
+    }
+
+    /// The position of the most populated city in each timezone.
+    pub fn timezone_positions(&self) -> BTreeMap<String, GeoPosition> {
+        let mut positions = BTreeMap::new();
+        for city in &self.cities {
+            positions
+                .entry(city.timezone.clone())
+                .or_insert_with(|| city.position.clone());
+        }
+        positions
+    }
+
+    /// Cities whose name starts with the query, ignoring case, most populated first.
+    pub fn search_prefix(&self, query: &str, limit: usize) -> Vec<&City> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        self.cities
+            .iter()
+            .filter(|city| {
+                city.name.to_lowercase().starts_with(&query)
+                    || city.ascii_name.to_lowercase().starts_with(&query)
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Cities whose name is within a few typos of the query, closest matches first.
+    ///
+    /// The query is compared against the start of each name, so partial names also match.
+    pub fn search_fuzzy(&self, query: &str, limit: usize) -> Vec<&City> {
+        let query: Vec<char> = query.trim().to_lowercase().chars().collect();
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let max_distance = (query.len() / 4).max(1);
+
+        let mut matches: Vec<(usize, &City)> = self
+            .cities
+            .iter()
+            .filter_map(|city| {
+                let distance = prefix_distance(&query, &city.name)
+                    .min(prefix_distance(&query, &city.ascii_name));
+                (distance <= max_distance).then_some((distance, city))
+            })
+            .collect();
+
+        // The sort is stable, so cities at the same distance stay ordered by population.
+        matches.sort_by_key(|(distance, _)| *distance);
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, city)| city)
+            .collect()
+    }
+
+    /// The city closest to the given coordinates.
+    pub fn nearest(&self, latitude: f64, longitude: f64) -> Option<&City> {
+        let position = GeoPosition {
+            latitude,
+            longitude,
+        };
+
+        self.cities.iter().min_by(|a, b| {
+            distance_km(&position, &a.position).total_cmp(&distance_km(&position, &b.position))
+        })
+    }
+}
+
+/// Great-circle distance between two positions in kilometers.
+pub fn distance_km(a: &GeoPosition, b: &GeoPosition) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_long = (b.longitude - a.longitude).to_radians();
+
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_long / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+/// Edit distance between the query and the start of a name of the same length, counting
+/// swapped neighbouring letters as a single edit.
+fn prefix_distance(query: &[char], name: &str) -> usize {
+    let name: Vec<char> = name.to_lowercase().chars().take(query.len()).collect();
+
+    let mut d = vec![vec![0; name.len() + 1]; query.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=query.len() {
+        for j in 1..=name.len() {
+            let cost = usize::from(query[i - 1] != name[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && query[i - 1] == name[j - 2] && query[i - 2] == name[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[query.len()][name.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city(id: u32, name: &str, population: u64, latitude: f64, longitude: f64) -> City {
+        City {
+            id,
+            name: name.to_owned(),
+            ascii_name: name.to_owned(),
+            country_code: String::from("NO"),
+            admin1: String::new(),
+            population,
+            timezone: String::from("Europe/Oslo"),
+            position: GeoPosition {
+                latitude,
+                longitude,
+            },
+        }
+    }
+
+    fn database() -> Database {
+        let mut tromso = city(3133880, "Tromsø", 64_000, 69.65, 18.96);
+        tromso.ascii_name = String::from("Tromso");
+
+        Database::new(vec![
+            city(3161732, "Bergen", 213_585, 60.39, 5.32),
+            tromso,
+            city(3143244, "Oslo", 580_000, 59.91, 10.75),
+            city(3141310, "Trondheim", 147_139, 63.43, 10.39),
+        ])
+    }
+
+    #[test]
+    fn versioned_round_trip() {
+        let db = database();
+        let data = db.encode();
+        assert_eq!(Database::decode(&data).unwrap(), db);
+
+        let mut future = data.clone();
+        future[..4].copy_from_slice(&(SCHEMA_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Database::decode(&future),
+            Err(Error::Version { found, .. }) if found == SCHEMA_VERSION + 1
+        ));
+        assert!(matches!(
+            Database::decode(&data[..2]),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn search() {
+        let db = database();
+
+        let names = |cities: Vec<&City>| cities.iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(names(db.search_prefix("tro", 10)), [3141310, 3133880]);
+        assert_eq!(names(db.search_prefix("TROMSO", 10)), [3133880]);
+        assert_eq!(names(db.search_prefix("tro", 1)), [3141310]);
+        assert!(db.search_prefix(" ", 10).is_empty());
+
+        assert_eq!(names(db.search_fuzzy("Trondhiem", 10)), [3141310]);
+        assert_eq!(names(db.search_fuzzy("bregen", 10)), [3161732]);
+
+        assert_eq!(db.nearest(69.0, 19.0).unwrap().id, 3133880);
+        assert_eq!(db.nearest(60.0, 10.0).unwrap().id, 3143244);
+
+        let positions = db.timezone_positions();
+        assert_eq!(positions["Europe/Oslo"].latitude, 59.91);
+    }
+}
@@ -1,36 +1,236 @@
-use geonames::GeoPosition;
-use std::collections::BTreeMap;
+use geonames::{City, Database, GeoPosition};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read};
+use std::path::PathBuf;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // There are files with a threshold of 500, 1000, 5000, and 15000.
-    let threshold = 5000;
-    let url = format!(
-        "https://download.geonames.org/export/dump/cities{}.zip",
-        threshold
-    );
-    println!("Downloading {}", url);
-    let mut response = reqwest::get(url).await?;
-    let length = response.content_length().unwrap_or(0) as usize;
-    let mut data = Vec::with_capacity(length);
-    while let Some(chunk) = response.chunk().await? {
-        data.extend_from_slice(&chunk);
-        print!("\rzip: {}/{}", data.len(), length);
+const USAGE: &str = "\
+Generates the geodata embedded in cosmic-settings-daemon from the geonames cities dump.
+
+USAGE:
+    geonames [OPTIONS]
+
+OPTIONS:
+    -i, --input <PATH>       Local citiesN.zip or citiesN.txt to read instead of downloading
+    -a, --admin1 <PATH>      Local admin1CodesASCII.txt used to name administrative regions
+    -t, --threshold <N>      Skip cities with fewer than N people [default: 5000]
+    -f, --format <FORMAT>    `timezones` or `cities` [default: timezones]
+        --fields <LIST>      Comma-separated city fields to keep: name,country,admin1,population
+                             [default: name,country,admin1,population]
+    -o, --output <PATH>      Where to write the encoded geodata
+                             [default: ../data/timezone-geodata.bitcode-v0-6 for timezones,
+                              ../data/geonames-cities.bitcode-v0-6 for cities]
+    -h, --help               Print this help
+
+Without --input, cities{500,1000,5000,15000}.zip is downloaded from download.geonames.org
+for the given threshold, which requires the `download` feature.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// Position of the most populated city in each timezone.
+    Timezones,
+    /// Every city, with a versioned schema.
+    Cities,
+}
+
+/// City fields which may be left out of the cities database.
+#[derive(Clone, Copy, Debug)]
+struct Fields {
+    name: bool,
+    country: bool,
+    admin1: bool,
+    population: bool,
+}
+
+impl Fields {
+    const ALL: Self = Self {
+        name: true,
+        country: true,
+        admin1: true,
+        population: true,
+    };
+
+    fn parse(list: &str) -> Result<Self, String> {
+        let mut fields = Self {
+            name: false,
+            country: false,
+            admin1: false,
+            population: false,
+        };
+
+        for field in list.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "name" => fields.name = true,
+                "country" => fields.country = true,
+                "admin1" => fields.admin1 = true,
+                "population" => fields.population = true,
+                _ => return Err(format!("unknown field `{field}`")),
+            }
+        }
+
+        Ok(fields)
     }
-    println!();
 
-    let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
-    let file = zip.by_name(&format!("cities{}.txt", threshold))?;
-    let bufread = io::BufReader::new(file);
-    let mut sorted_data = Vec::new();
-    for line_res in bufread.lines() {
+    fn strip(self, city: &mut City) {
+        if !self.name {
+            city.name.clear();
+            city.ascii_name.clear();
+        }
+        if !self.country {
+            city.country_code.clear();
+        }
+        if !self.admin1 {
+            city.admin1.clear();
+        }
+        if !self.population {
+            city.population = 0;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    input: Option<PathBuf>,
+    admin1: Option<PathBuf>,
+    threshold: u64,
+    format: Format,
+    fields: Fields,
+    output: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut parsed = Self {
+            input: None,
+            admin1: None,
+            threshold: 5000,
+            format: Format::Timezones,
+            fields: Fields::ALL,
+            output: None,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for `{arg}`"))
+            };
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-i" | "--input" => parsed.input = Some(value()?.into()),
+                "-a" | "--admin1" => parsed.admin1 = Some(value()?.into()),
+                "-t" | "--threshold" => {
+                    let value = value()?;
+                    parsed.threshold = value
+                        .parse()
+                        .map_err(|_| format!("invalid threshold `{value}`"))?;
+                }
+                "-f" | "--format" => {
+                    parsed.format = match value()?.as_str() {
+                        "timezones" => Format::Timezones,
+                        "cities" => Format::Cities,
+                        other => return Err(format!("unknown format `{other}`")),
+                    }
+                }
+                "--fields" => parsed.fields = Fields::parse(&value()?)?,
+                "-o" | "--output" => parsed.output = Some(value()?.into()),
+                _ => return Err(format!("unexpected argument `{arg}`")),
+            }
+        }
+
+        Ok(Some(parsed))
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        Err(why) => {
+            eprintln!("error: {why}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let cities_txt = match &args.input {
+        Some(path) if path.extension().is_some_and(|ext| ext == "zip") => {
+            unzip_cities(fs::read(path)?)?
+        }
+        Some(path) => fs::read(path)?,
+        None => unzip_cities(download_cities(args.threshold)?)?,
+    };
+
+    let admin1_names = match &args.admin1 {
+        Some(path) => admin1_names(&fs::read(path)?)?,
+        None if args.input.is_none() => admin1_names(&download(
+            "https://download.geonames.org/export/dump/admin1CodesASCII.txt",
+        )?)?,
+        None => {
+            eprintln!("no --admin1 file given, keeping administrative region codes");
+            HashMap::new()
+        }
+    };
+
+    let cities = parse_cities(&cities_txt, &admin1_names, args.threshold)?;
+    println!("cities: {}", cities.len());
+
+    let (output, encoded) = match args.format {
+        Format::Timezones => {
+            let timezone_positions = Database::new(cities).timezone_positions();
+            println!("timezone-geodata: {}", timezone_positions.len());
+
+            let encoded = geonames::encode_timezone_positions(&timezone_positions);
+            if geonames::decode_timezone_positions(&encoded)? != timezone_positions {
+                return Err("encoded timezone geodata does not decode to the same data".into());
+            }
+
+            ("../data/timezone-geodata.bitcode-v0-6", encoded)
+        }
+
+        Format::Cities => {
+            let database = Database::new(
+                cities
+                    .into_iter()
+                    .map(|mut city| {
+                        args.fields.strip(&mut city);
+                        city
+                    })
+                    .collect(),
+            );
+
+            let encoded = database.encode();
+            if Database::decode(&encoded)? != database {
+                return Err("encoded city database does not decode to the same data".into());
+            }
+
+            ("../data/geonames-cities.bitcode-v0-6", encoded)
+        }
+    };
+
+    let output = args.output.unwrap_or_else(|| PathBuf::from(output));
+    println!("bitcode: {}", encoded.len());
+    fs::write(&output, encoded)?;
+    println!("wrote {}", output.display());
+
+    Ok(())
+}
+
+/// Reads cities with at least `threshold` people from a geonames dump.
+fn parse_cities(
+    data: &[u8],
+    admin1_names: &HashMap<String, String>,
+    threshold: u64,
+) -> Result<Vec<City>, Box<dyn std::error::Error>> {
+    let mut cities = Vec::new();
+    for line_res in data.lines() {
         let line = line_res?;
         let mut parts = line.split('\t');
-        let Some(_id) = parts.next() else { continue };
-        let Some(_name) = parts.next() else { continue };
-        let Some(_ascii_name) = parts.next() else {
+        let Some(id) = parts.next() else { continue };
+        let Some(name) = parts.next() else { continue };
+        let Some(ascii_name) = parts.next() else {
             continue;
         };
         let Some(_alternate_names) = parts.next() else {
@@ -48,13 +248,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let Some(_feature_code) = parts.next() else {
             continue;
         };
-        let Some(_country_code) = parts.next() else {
+        let Some(country_code) = parts.next() else {
             continue;
         };
         let Some(_alternate_country_codes) = parts.next() else {
             continue;
         };
-        let Some(_admin1_code) = parts.next() else {
+        let Some(admin1_code) = parts.next() else {
             continue;
         };
         let Some(_admin2_code) = parts.next() else {
@@ -82,31 +282,141 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             continue;
         };
 
-        let geoposition = GeoPosition {
-            latitude: latitude.parse()?,
-            longitude: longitude.parse()?,
-        };
+        let population = population.parse()?;
+        if population < threshold {
+            continue;
+        }
 
-        let timezone = timezone.to_string();
-        sorted_data.push((population.parse::<u64>()?, timezone, geoposition));
+        let admin1 = admin1_names
+            .get(&format!("{country_code}.{admin1_code}"))
+            .cloned()
+            .unwrap_or_else(|| admin1_code.to_string());
+
+        cities.push(City {
+            id: id.parse()?,
+            name: name.to_string(),
+            ascii_name: ascii_name.to_string(),
+            country_code: country_code.to_string(),
+            admin1,
+            population,
+            timezone: timezone.to_string(),
+            position: GeoPosition {
+                latitude: latitude.parse()?,
+                longitude: longitude.parse()?,
+            },
+        });
     }
 
-    sorted_data.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(cities)
+}
+
+/// Extracts the cities text file from a geonames zip archive.
+fn unzip_cities(data: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
+    let name = zip
+        .file_names()
+        .find(|name| name.starts_with("cities") && name.ends_with(".txt"))
+        .ok_or("zip archive does not contain a cities text file")?
+        .to_owned();
+
+    let mut txt = Vec::new();
+    zip.by_name(&name)?.read_to_end(&mut txt)?;
+    Ok(txt)
+}
+
+/// Map `<country code>.<admin1 code>` keys to the names of administrative regions.
+fn admin1_names(data: &[u8]) -> io::Result<HashMap<String, String>> {
+    let mut names = HashMap::new();
+    for line in data.lines() {
+        let line = line?;
+        let mut parts = line.split('\t');
+        if let (Some(code), Some(name)) = (parts.next(), parts.next()) {
+            names.insert(code.to_string(), name.to_string());
+        }
+    }
+    Ok(names)
+}
 
-    let mut timezone_positions = BTreeMap::new();
-    for (_, timezone, geoposition) in sorted_data {
-        timezone_positions.entry(timezone).or_insert(geoposition);
+fn download_cities(threshold: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // There are files with a threshold of 500, 1000, 5000, and 15000.
+    if ![500, 1000, 5000, 15000].contains(&threshold) {
+        return Err(format!(
+            "no cities file to download for a threshold of {threshold}, use 500, 1000, 5000, or 15000"
+        )
+        .into());
     }
 
-    for (timezone, geoposition) in &timezone_positions {
-        eprintln!("{timezone}: {geoposition:?}");
+    download(&format!(
+        "https://download.geonames.org/export/dump/cities{}.zip",
+        threshold
+    ))
+}
+
+#[cfg(feature = "download")]
+fn download(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    tokio::runtime::Runtime::new()?.block_on(async {
+        println!("Downloading {}", url);
+        let mut response = reqwest::get(url).await?;
+        let length = response.content_length().unwrap_or(0) as usize;
+        let mut data = Vec::with_capacity(length);
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            print!("\r{}/{}", data.len(), length);
+        }
+        println!();
+        Ok(data)
+    })
+}
+
+#[cfg(not(feature = "download"))]
+fn download(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Err(
+        format!("cannot download {url} without the `download` feature, pass --input instead")
+            .into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CITIES: &str = "\
+3143244\tOslo\tOslo\tOslo\t59.91273\t10.74609\tP\tPPLC\tNO\t\t12\t0301\t\t\t580000\t\t26\tEurope/Oslo\t2022-12-07
+3133880\tTromsø\tTromso\tTromso\t69.6489\t18.95508\tP\tPPLA\tNO\t\t56\t5501\t\t\t64000\t\t10\tEurope/Oslo\t2024-01-01
+3161732\tBergen\tBergen\tBergen\t60.39299\t5.32415\tP\tPPLA\tNO\t\t46\t4601\t\t\t213585\t\t20\tEurope/Oslo\t2022-12-07
+";
+
+    #[test]
+    fn parse_args() {
+        let args = |list: &[&str]| Args::parse(list.iter().map(|s| s.to_string()));
+
+        let parsed = args(&["-i", "cities500.zip", "--threshold", "1000", "-f", "cities"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.input, Some(PathBuf::from("cities500.zip")));
+        assert_eq!(parsed.threshold, 1000);
+        assert_eq!(parsed.format, Format::Cities);
+
+        assert!(args(&["--help"]).unwrap().is_none());
+        assert!(args(&["--threshold"]).is_err());
+        assert!(args(&["--fields", "name,elevation"]).is_err());
     }
 
-    println!("timezone-geodata: {}", timezone_positions.len());
+    #[test]
+    fn parse_offline_dump() {
+        let admin1 = admin1_names(b"NO.12\tOslo County\tOslo\t3143242\n").unwrap();
+        let cities = parse_cities(CITIES.as_bytes(), &admin1, 100_000).unwrap();
 
-    let bitcode = bitcode::encode(&timezone_positions);
-    println!("bitcode: {}", bitcode.len());
-    fs::write("../data/timezone-geodata.bitcode-v0-6", bitcode)?;
+        assert_eq!(cities.len(), 2);
+        assert_eq!(cities[0].admin1, "Oslo County");
+        assert_eq!(cities[1].admin1, "46");
 
-    Ok(())
+        let positions = Database::new(cities).timezone_positions();
+        let encoded = geonames::encode_timezone_positions(&positions);
+        assert_eq!(
+            geonames::decode_timezone_positions(&encoded).unwrap(),
+            positions
+        );
+        assert_eq!(positions["Europe/Oslo"].latitude, 59.91273);
+    }
 }
@@ -0,0 +1,265 @@
+use cosmic::config::CosmicTk;
+use cosmic_config::{ConfigGet, ConfigSet, CosmicConfigEntry};
+use cosmic_dbus_a11y::StatusProxy;
+use cosmic_settings_daemon_config::greeter;
+use cosmic_theme::{CosmicPalette, ThemeBuilder};
+use tokio_stream::StreamExt;
+
+/// Text scaling factor used by GNOME's own large text setting.
+const LARGE_TEXT_SCALING_FACTOR: f64 = 1.25;
+
+/// Whether the themes use their high contrast palettes.
+pub fn high_contrast() -> bool {
+    let Ok(builder_config) = ThemeBuilder::dark_config() else {
+        return false;
+    };
+
+    let builder = match ThemeBuilder::get_entry(&builder_config) {
+        Ok(builder) => builder,
+        Err((_, builder)) => builder,
+    };
+
+    matches!(
+        builder.palette,
+        CosmicPalette::HighContrastDark(_) | CosmicPalette::HighContrastLight(_)
+    )
+}
+
+pub fn set_high_contrast(enabled: bool) -> Result<(), cosmic_config::Error> {
+    greeter::apply_hc_theme(enabled)?;
+    sync_high_contrast(enabled);
+    Ok(())
+}
+
+/// Mirror the high contrast setting to GNOME applications.
+pub fn sync_high_contrast(enabled: bool) {
+    gsettings_set(
+        "org.gnome.desktop.a11y.interface",
+        "high-contrast",
+        enabled.to_string(),
+    );
+}
+
+/// CosmicTk key for reducing non-essential animations.
+pub const TK_REDUCE_MOTION_KEY: &str = "reduce_motion";
+/// CosmicTk key for enlarging text.
+pub const TK_LARGE_TEXT_KEY: &str = "large_text";
+
+/// Whether non-essential animations are disabled.
+pub fn reduce_motion() -> bool {
+    tk_flag(TK_REDUCE_MOTION_KEY)
+}
+
+pub fn set_reduce_motion(enabled: bool) -> Result<(), cosmic_config::Error> {
+    CosmicTk::config()?.set(TK_REDUCE_MOTION_KEY, enabled)?;
+    sync_reduce_motion(enabled);
+    Ok(())
+}
+
+/// Mirror the reduce motion setting to GNOME applications.
+pub fn sync_reduce_motion(enabled: bool) {
+    gsettings_set(
+        "org.gnome.desktop.interface",
+        "enable-animations",
+        (!enabled).to_string(),
+    );
+}
+
+/// Whether text is enlarged.
+pub fn large_text() -> bool {
+    tk_flag(TK_LARGE_TEXT_KEY)
+}
+
+pub fn set_large_text(enabled: bool) -> Result<(), cosmic_config::Error> {
+    CosmicTk::config()?.set(TK_LARGE_TEXT_KEY, enabled)?;
+    sync_large_text(enabled);
+    Ok(())
+}
+
+/// Mirror the large text setting to GNOME applications.
+pub fn sync_large_text(enabled: bool) {
+    let factor = if enabled {
+        LARGE_TEXT_SCALING_FACTOR
+    } else {
+        1.0
+    };
+
+    gsettings_set(
+        "org.gnome.desktop.interface",
+        "text-scaling-factor",
+        factor.to_string(),
+    );
+}
+
+/// A CosmicTk flag which libcosmic has no field for, and so may not be set yet.
+fn tk_flag(key: &str) -> bool {
+    CosmicTk::config()
+        .and_then(|config| config.get::<bool>(key))
+        .unwrap_or(false)
+}
+
+fn gsettings_set(schema: &'static str, key: &'static str, value: String) {
+    tokio::spawn(async move {
+        if let Err(err) = tokio::process::Command::new("gsettings")
+            .args(["set", schema, key, &value])
+            .status()
+            .await
+        {
+            log::error!("Failed to set {schema} {key}: {err:?}");
+        }
+    });
+}
+
+const A11Y_BUS_NAME: &str = "org.a11y.Bus";
+
+/// Screen reader state shared between the D-Bus property and the task which applies it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScreenReaderState {
+    /// Whether the screen reader is enabled, as last reported by the accessibility bus.
+    pub enabled: bool,
+    /// State waiting for the accessibility bus to become available.
+    pub pending: Option<bool>,
+}
+
+impl ScreenReaderState {
+    /// The state that the screen reader is in, or will be in once the pending state is applied.
+    pub fn desired(self) -> bool {
+        self.pending.unwrap_or(self.enabled)
+    }
+}
+
+/// Apply requested screen reader states whenever the accessibility bus is available, and follow
+/// changes made to it by other tools.
+pub async fn screen_reader_task(state: tokio::sync::watch::Sender<ScreenReaderState>) {
+    let conn = match zbus::Connection::session().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::error!("Failed to connect to session message bus: {err:?}");
+            return;
+        }
+    };
+
+    if let Err(err) = follow_screen_reader(&conn, &state).await {
+        log::error!("Stopped following the screen reader state: {err:?}");
+    }
+}
+
+async fn follow_screen_reader(
+    conn: &zbus::Connection,
+    state: &tokio::sync::watch::Sender<ScreenReaderState>,
+) -> zbus::Result<()> {
+    let dbus = zbus::fdo::DBusProxy::new(conn).await?;
+    let mut owner_changes = dbus
+        .receive_name_owner_changed_with_args(&[(0, A11Y_BUS_NAME)])
+        .await?;
+
+    let status = StatusProxy::builder(conn)
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await?;
+
+    let mut property_changes = zbus::fdo::PropertiesProxy::builder(conn)
+        .destination(A11Y_BUS_NAME)?
+        .path("/org/a11y/bus")?
+        .build()
+        .await?
+        .receive_properties_changed()
+        .await?;
+
+    let mut requests = state.subscribe();
+    let mut available = dbus
+        .name_has_owner(A11Y_BUS_NAME.try_into()?)
+        .await
+        .unwrap_or(false);
+
+    if available && let Ok(enabled) = status.screen_reader_enabled().await {
+        update_screen_reader_enabled(state, enabled);
+    }
+
+    loop {
+        let pending = requests.borrow_and_update().pending;
+        if available && let Some(enabled) = pending {
+            match apply_screen_reader(&status, enabled).await {
+                Ok(()) => {
+                    state.send_modify(|state| {
+                        if state.pending == Some(enabled) {
+                            state.pending = None;
+                        }
+                    });
+                    update_screen_reader_enabled(state, enabled);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Failed to apply the screen reader state, will retry when the accessibility bus restarts: {err:?}"
+                    );
+                    available = false;
+                }
+            }
+        }
+
+        tokio::select! {
+            changed = requests.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+
+            signal = owner_changes.next() => {
+                let Some(signal) = signal else {
+                    return Ok(());
+                };
+
+                available = signal.args().is_ok_and(|args| args.new_owner.is_some());
+                if available
+                    && state.borrow().pending.is_none()
+                    && let Ok(enabled) = status.screen_reader_enabled().await
+                {
+                    update_screen_reader_enabled(state, enabled);
+                }
+            }
+
+            signal = property_changes.next() => {
+                let Some(signal) = signal else {
+                    return Ok(());
+                };
+
+                let Ok(args) = signal.args() else {
+                    continue;
+                };
+
+                if args.interface_name == "org.a11y.Status"
+                    && let Some(enabled) = args
+                        .changed_properties
+                        .get("ScreenReaderEnabled")
+                        .and_then(|value| value.downcast_ref::<bool>().ok())
+                {
+                    update_screen_reader_enabled(state, enabled);
+                }
+            }
+        }
+    }
+}
+
+async fn apply_screen_reader(status: &StatusProxy<'_>, enabled: bool) -> zbus::Result<()> {
+    status.set_is_enabled(enabled).await?;
+    status.set_screen_reader_enabled(enabled).await
+}
+
+fn update_screen_reader_enabled(
+    state: &tokio::sync::watch::Sender<ScreenReaderState>,
+    enabled: bool,
+) {
+    let modified = state.send_if_modified(|state| {
+        let modified = state.enabled != enabled;
+        state.enabled = enabled;
+        modified
+    });
+
+    if modified
+        && let Err(err) = crate::greeter::save_for_greeter(|state| {
+            state.screen_reader = Some(enabled);
+        })
+    {
+        log::error!("Failed to save screen reader state for the greeter: {err:?}");
+    }
+}
@@ -0,0 +1,34 @@
+//! Read-only view of the daemon's internal state, for debugging.
+
+use crate::WatchedEntries;
+
+pub const DEBUG_PATH: &str = "/com/system76/CosmicSettingsDaemon/Debug";
+
+pub struct DaemonDebug {
+    pub watched: WatchedEntries,
+}
+
+#[zbus::interface(name = "com.system76.CosmicSettingsDaemon.Debug")]
+impl DaemonDebug {
+    /// Every watched config and state entry, as `(kind, id, version, bus name, watchers)`. The
+    /// kind is `Config`, `State`, `ConfigV2` or `StateV2`.
+    async fn watches(&self) -> Vec<(String, String, u64, String, Vec<String>)> {
+        let mut watches = Vec::new();
+        for (kind, configs) in self.watched.all() {
+            for ((id, version), watched) in configs.read().await.iter() {
+                let mut watchers: Vec<String> =
+                    watched.watchers.iter().map(ToString::to_string).collect();
+                watchers.sort();
+                watches.push((
+                    kind.to_owned(),
+                    id.clone(),
+                    *version,
+                    watched.name.to_string(),
+                    watchers,
+                ));
+            }
+        }
+        watches.sort();
+        watches
+    }
+}
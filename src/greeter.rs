@@ -1,13 +1,16 @@
-use std::time::Duration;
-
 use cosmic_config::CosmicConfigEntry;
-use cosmic_dbus_a11y::*;
-use cosmic_settings_daemon_config::greeter;
+use cosmic_settings_daemon_config::greeter::{self, GreeterAccessibilityState};
+
+use crate::accessibility::{self, ScreenReaderState};
+use crate::wayland::A11yState;
 
-pub fn sync_with_greeter() -> anyhow::Result<()> {
+pub fn sync_with_greeter(
+    screen_reader_tx: &tokio::sync::watch::Sender<ScreenReaderState>,
+    a11y_tx: &tokio::sync::watch::Sender<A11yState>,
+) -> anyhow::Result<()> {
     log::trace!("syncing with greeter...");
-    let helper = greeter::GreeterAccessibilityState::config()?;
-    let state = match greeter::GreeterAccessibilityState::get_entry(&helper) {
+    let helper = GreeterAccessibilityState::config()?;
+    let state = match GreeterAccessibilityState::get_entry(&helper) {
         Ok(s) => s,
         Err((errs, s)) => {
             for err in errs {
@@ -23,29 +26,75 @@ pub fn sync_with_greeter() -> anyhow::Result<()> {
         log::error!("Failed to apply high contrast changes from the greeter: {err:?}");
     }
 
+    // Applied once the compositor is connected.
+    a11y_tx.send_modify(|a11y| {
+        a11y.pending_magnifier = state.magnifier;
+        a11y.pending_invert_colors = state.invert_colors;
+    });
+
     if let Some(screen_reader) = state.screen_reader {
-        tokio::spawn(async move {
-            for _ in 0..5 {
-                let conn = match zbus::Connection::session().await.map_err(|e| e.to_string()) {
-                    Ok(conn) => conn,
-                    Err(err) => {
-                        log::error!("Failed to connect to session message bus: {err:?}");
-                        _ = tokio::time::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                };
-                if let Ok(proxy) = StatusProxy::new(&conn).await
-                    && let Err(err) = proxy.set_screen_reader_enabled(screen_reader).await
-                {
-                    log::error!("Failed to apply screen reader status. {err:?}");
-                    continue;
-                }
-                break;
-            }
-        });
+        // Applied once the accessibility bus is available.
+        screen_reader_tx.send_modify(|state| state.pending = Some(screen_reader));
     }
 
     log::trace!("applied greeter state");
 
+    // Anything the greeter did not set comes from the user's own settings. The magnifier and
+    // inverted colors are saved once the compositor reports them.
+    if let Err(err) = save_for_greeter(|saved| {
+        saved.high_contrast = Some(
+            state
+                .high_contrast
+                .unwrap_or_else(accessibility::high_contrast),
+        );
+    }) {
+        log::error!("Failed to save accessibility state for the greeter: {err:?}");
+    }
+
     Ok(())
 }
+
+/// Update the accessibility state which the greeter opens with, and applies at the next login.
+pub fn save_for_greeter(
+    update: impl FnOnce(&mut GreeterAccessibilityState),
+) -> Result<(), cosmic_config::Error> {
+    let helper = GreeterAccessibilityState::config()?;
+    let mut state = match GreeterAccessibilityState::get_entry(&helper) {
+        Ok(s) => s,
+        Err((_, s)) => s,
+    };
+
+    let previous = state.clone();
+    update(&mut state);
+    if state != previous {
+        state.write_entry(&helper)?;
+    }
+
+    Ok(())
+}
+
+/// Save the compositor's magnifier and inverted colors for the greeter whenever they change.
+pub async fn save_compositor_a11y(mut a11y_rx: tokio::sync::watch::Receiver<A11yState>) {
+    let mut saved = None;
+    while a11y_rx.changed().await.is_ok() {
+        let a11y = *a11y_rx.borrow_and_update();
+
+        // Wait for the compositor to report the state once the changes have been applied.
+        if a11y.pending_magnifier.is_some() || a11y.pending_invert_colors.is_some() {
+            continue;
+        }
+
+        let current = (a11y.magnifier, a11y.invert_colors);
+        if saved == Some(current) {
+            continue;
+        }
+
+        saved = Some(current);
+        if let Err(err) = save_for_greeter(|state| {
+            state.magnifier = Some(a11y.magnifier);
+            state.invert_colors = Some(a11y.invert_colors);
+        }) {
+            log::error!("Failed to save magnifier and inverted colors for the greeter: {err:?}");
+        }
+    }
+}
@@ -1,13 +1,149 @@
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 use anyhow::Context;
 use cosmic_comp_config::XkbConfig;
 use cosmic_config::{ConfigGet, ConfigSet};
+use cosmic_settings_daemon_config::{CosmicSettingsDaemonConfig, KeyboardLayoutSource};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use tokio_stream::StreamExt;
 
 pub const COSMIC_COMP_ID: &str = "com.system76.CosmicComp";
 pub const COSMIC_COMP_XDG_KEY: &str = "xkb_config";
+/// Settings daemon config key for [`KeyboardLayoutSource`].
+pub const KEYBOARD_LAYOUT_SOURCE_KEY: &str = "keyboard_layout_source";
+
+/// How long localed must be quiet before a burst of X11 property changes is synced.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// The X11 keyboard properties of localed, which are synced together as one snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct X11Keyboard {
+    model: String,
+    layout: String,
+    variant: String,
+    options: String,
+}
+
+impl X11Keyboard {
+    fn from_xkb(xkb_config: &XkbConfig) -> Self {
+        Self {
+            model: xkb_config.model.clone(),
+            layout: xkb_config.layout.clone(),
+            variant: xkb_config.variant.clone(),
+            options: xkb_config.options.clone().unwrap_or_default(),
+        }
+    }
+
+    fn to_xkb(&self, current: XkbConfig) -> XkbConfig {
+        XkbConfig {
+            model: self.model.clone(),
+            layout: self.layout.clone(),
+            variant: self.variant.clone(),
+            options: match self.options.as_str() {
+                "" => None,
+                x => Some(x.to_string()),
+            },
+            ..current
+        }
+    }
+
+    async fn read(proxy: &locale1::locale1Proxy<'_>) -> zbus::Result<Self> {
+        let (model, layout, variant, options) = futures_util::try_join!(
+            proxy.x11model(),
+            proxy.x11layout(),
+            proxy.x11variant(),
+            proxy.x11options()
+        )?;
+
+        Ok(Self {
+            model,
+            layout,
+            variant,
+            options,
+        })
+    }
+
+    async fn write(&self, proxy: &locale1::locale1Proxy<'_>) -> zbus::Result<()> {
+        proxy
+            .set_x11keyboard(
+                &self.layout,
+                &self.model,
+                &self.variant,
+                &self.options,
+                true,
+                false,
+            )
+            .await
+    }
+}
+
+/// Write the layout to localed, unless it is the layout already synced. Returns whether it was
+/// written.
+async fn push_x11_keyboard(
+    proxy: &locale1::locale1Proxy<'_>,
+    synced: &mut Option<X11Keyboard>,
+    keyboard: X11Keyboard,
+) -> zbus::Result<bool> {
+    if synced.as_ref() == Some(&keyboard) {
+        return Ok(false);
+    }
+
+    keyboard.write(proxy).await?;
+    *synced = Some(keyboard);
+    Ok(true)
+}
+
+/// Read the layout from localed. Returns `None` if it is the layout already synced, such as when
+/// localed signals the daemon's own write.
+async fn pull_x11_keyboard(
+    proxy: &locale1::locale1Proxy<'_>,
+    synced: &mut Option<X11Keyboard>,
+) -> zbus::Result<Option<X11Keyboard>> {
+    let keyboard = X11Keyboard::read(proxy).await?;
+    if synced.as_ref() == Some(&keyboard) {
+        return Ok(None);
+    }
+
+    *synced = Some(keyboard.clone());
+    Ok(Some(keyboard))
+}
+
+/// Changes to any of the X11 keyboard properties of localed.
+async fn x11_keyboard_changes<'a>(
+    proxy: &'a locale1::locale1Proxy<'a>,
+) -> std::pin::Pin<Box<dyn tokio_stream::Stream<Item = ()> + 'a>> {
+    let model = proxy.receive_x11model_changed().await.map(drop);
+    let layout = proxy.receive_x11layout_changed().await.map(drop);
+    let variant = proxy.receive_x11variant_changed().await.map(drop);
+    let options = proxy.receive_x11options_changed().await.map(drop);
+    Box::pin(model.merge(layout).merge(variant).merge(options))
+}
+
+/// Debounces bursts of changes, so that a single layout change, which localed signals as up to
+/// four property changes, is synced once.
+#[derive(Default)]
+struct Debounce {
+    deadline: Option<tokio::time::Instant>,
+}
+
+impl Debounce {
+    fn changed(&mut self) {
+        self.deadline = Some(tokio::time::Instant::now() + SETTLE_TIME);
+    }
+
+    /// Wait for the current burst of changes to end. This is cancel safe.
+    async fn settled(&mut self) {
+        match self.deadline {
+            Some(deadline) => {
+                tokio::time::sleep_until(deadline).await;
+                self.deadline = None;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
 
 pub async fn sync_locale1(mut rx: Receiver<()>) -> anyhow::Result<()> {
     let conn = zbus::Connection::system().await?;
@@ -15,105 +151,458 @@ pub async fn sync_locale1(mut rx: Receiver<()>) -> anyhow::Result<()> {
     let config = cosmic_config::Config::new(COSMIC_COMP_ID, 1)
         .context("Found no cosmic-comp configuration")?;
 
-    let mut model_stream = proxy.receive_x11model_changed().await;
-    let mut layout_stream = proxy.receive_x11layout_changed().await;
-    let mut variant_stream = proxy.receive_x11variant_changed().await;
-    let mut options_stream = proxy.receive_x11options_changed().await;
+    let mut x11_changes = x11_keyboard_changes(&proxy).await;
+    let mut debounce = Debounce::default();
+    let mut vconsole_stream = proxy.receive_vconsole_keymap_changed().await;
 
-    // Consume events that are emitted on startup.
-    _ = tokio::time::timeout(Duration::from_secs(1), async {
-        loop {
-            tokio::select! {
-                _ = model_stream.next() => (),
-                _ = layout_stream.next() => (),
-                _ = variant_stream.next() => (),
-                _ = options_stream.next() => (),
-            }
-        }
-    })
-    .await;
+    let source = CosmicSettingsDaemonConfig::config()
+        .and_then(|config| config.get(KEYBOARD_LAYOUT_SOURCE_KEY))
+        .unwrap_or_default();
+
+    // The layout which COSMIC and localed last agreed on. Changes to it on either side are the
+    // daemon's own writes, and are not echoed back.
+    let mut synced = None;
+
+    // The console keymap last seen while the X11 layout matched the xkb config, so that keymap
+    // changes which localed converted from the X11 layout are not converted back.
+    let mut known_keymap = None;
 
-    // Sync on startup only if the xkb config is not set.
-    if config.get::<XkbConfig>(COSMIC_COMP_XDG_KEY).is_err() {
-        _ = sync_locale1_to_cosmic(&config, &proxy).await;
-    } else {
-        _ = sync_cosmic_to_locale1(&config, &proxy).await;
+    // On startup, the preferred side wins if it has a layout.
+    let cosmic_set = config.get::<XkbConfig>(COSMIC_COMP_XDG_KEY).is_ok();
+    let system_set = proxy
+        .x11layout()
+        .await
+        .is_ok_and(|layout| !layout.is_empty());
+    let cosmic_wins = match source {
+        KeyboardLayoutSource::Cosmic => cosmic_set,
+        KeyboardLayoutSource::System => !system_set && cosmic_set,
+    };
+
+    if cosmic_wins {
+        match sync_cosmic_to_locale1(&config, &proxy, &mut synced).await {
+            Ok(keymap) => known_keymap = keymap,
+            Err(err) => log::error!("Failed to sync xkb_config to systemd-localed: {err:?}"),
+        }
+    } else if let Ok(keymap) = sync_locale1_to_cosmic(&config, &proxy, &mut synced).await {
+        known_keymap = Some(keymap);
     }
 
     loop {
         if let Err(err) = tokio::select! {
             received = rx.recv() => {
                 if received.is_some() {
-                    sync_cosmic_to_locale1(&config, &proxy).await
+                    sync_cosmic_to_locale1(&config, &proxy, &mut synced)
+                        .await
+                        .map(|keymap| {
+                            if keymap.is_some() {
+                                known_keymap = keymap;
+                            }
+                        })
                 } else {
                     Ok(())
                 }
             },
-            _ = model_stream.next() => sync_locale1_to_cosmic(&config, &proxy).await,
-            _ = layout_stream.next() => sync_locale1_to_cosmic(&config, &proxy).await,
-            _ = variant_stream.next() => sync_locale1_to_cosmic(&config, &proxy).await,
-            _ = options_stream.next() => sync_locale1_to_cosmic(&config, &proxy).await,
+            _ = vconsole_stream.next() => {
+                sync_vconsole_to_locale1(&config, &proxy, known_keymap.as_deref()).await
+            },
+            Some(()) = x11_changes.next() => {
+                debounce.changed();
+                Ok(())
+            },
+            () = debounce.settled() => {
+                sync_locale1_to_cosmic(&config, &proxy, &mut synced)
+                    .await
+                    .map(|keymap| known_keymap = Some(keymap))
+            },
         } {
             log::error!("Failed to sync xkb_config with systemd-localed: {}", err);
         };
     }
 }
 
+/// Set the system's X11 layout from the xkb config, and let localed convert it to the console
+/// keymap used by early boot prompts. Returns the console keymap that localed chose, or `None`
+/// if the layout was already synced.
 async fn sync_cosmic_to_locale1(
     config: &cosmic_config::Config,
     proxy: &locale1::locale1Proxy<'_>,
-) -> anyhow::Result<()> {
+    synced: &mut Option<X11Keyboard>,
+) -> anyhow::Result<Option<String>> {
     let xkb_config = config
         .get::<XkbConfig>(COSMIC_COMP_XDG_KEY)
         .context("xkb-config not set")?;
 
-    proxy
-        .set_x11keyboard(
-            &xkb_config.layout,
-            &xkb_config.model,
-            &xkb_config.variant,
-            xkb_config.options.as_deref().unwrap_or(""),
-            true,
-            false,
-        )
+    let keyboard = X11Keyboard::from_xkb(&xkb_config);
+    if !push_x11_keyboard(proxy, synced, keyboard)
         .await
-        .context("Failed to update systemd-locale1 from xkb_config")?;
-    Ok(())
+        .context("Failed to update systemd-locale1 from xkb_config")?
+    {
+        return Ok(None);
+    }
+
+    let keymap = proxy
+        .vconsole_keymap()
+        .await
+        .context("failed to get console keymap from locale1 daemon")?;
+
+    if keymap.is_empty() {
+        log::warn!(
+            "No console keymap matches the {} keyboard layout, so the console and boot prompts will use the default keymap",
+            xkb_config.layout
+        );
+    }
+
+    Ok(Some(keymap))
 }
 
-async fn sync_locale1_to_cosmic(
+/// Apply a console keymap set by another tool to the X11 layout, which then syncs to the xkb
+/// config. Keymaps which localed converted from the X11 layout are left alone.
+async fn sync_vconsole_to_locale1(
     config: &cosmic_config::Config,
     proxy: &locale1::locale1Proxy<'_>,
+    known_keymap: Option<&str>,
 ) -> anyhow::Result<()> {
-    let (model, layout, variant, options) = futures_util::try_join!(
-        proxy.x11model(),
+    let (keymap, keymap_toggle, layout, variant) = futures_util::try_join!(
+        proxy.vconsole_keymap(),
+        proxy.vconsole_keymap_toggle(),
         proxy.x11layout(),
-        proxy.x11variant(),
-        proxy.x11options()
+        proxy.x11variant()
     )
-    .context("failed to get xkb config from locale1 daemon")?;
+    .context("failed to get console keymap from locale1 daemon")?;
 
-    let current_config = config
+    if keymap.is_empty() || Some(keymap.as_str()) == known_keymap {
+        return Ok(());
+    }
+
+    // The X11 layout changed along with the keymap, and is synced on its own.
+    let xkb_config = config
         .get::<XkbConfig>(COSMIC_COMP_XDG_KEY)
         .unwrap_or_default();
+    if xkb_config.layout != layout || xkb_config.variant != variant {
+        return Ok(());
+    }
+
+    proxy
+        .set_vconsole_keyboard(&keymap, &keymap_toggle, true, false)
+        .await
+        .context("Failed to convert the console keymap to an X11 layout")?;
+    Ok(())
+}
+
+/// Set the xkb config from the system's X11 layout. Returns the console keymap, which localed
+/// may have converted along with the layout.
+async fn sync_locale1_to_cosmic(
+    config: &cosmic_config::Config,
+    proxy: &locale1::locale1Proxy<'_>,
+    synced: &mut Option<X11Keyboard>,
+) -> anyhow::Result<String> {
+    let (keyboard, keymap) =
+        futures_util::try_join!(pull_x11_keyboard(proxy, synced), proxy.vconsole_keymap())
+            .context("failed to get xkb config from locale1 daemon")?;
 
-    let new_config = XkbConfig {
-        model,
-        layout,
-        variant,
-        options: match options.as_str() {
-            "" => None,
-            x => Some(x.to_string()),
-        },
-        ..current_config.clone()
+    let Some(keyboard) = keyboard else {
+        return Ok(keymap);
     };
 
-    if new_config == current_config {
-        return Ok(());
+    let current_config = config
+        .get::<XkbConfig>(COSMIC_COMP_XDG_KEY)
+        .unwrap_or_default();
+
+    let new_config = keyboard.to_xkb(current_config.clone());
+    if new_config != current_config {
+        config
+            .set::<XkbConfig>(COSMIC_COMP_XDG_KEY, new_config)
+            .context("Failed to update xkb_config from systemd-localed")?;
+    }
+
+    Ok(keymap)
+}
+
+/// Environment variables which make up the system locale.
+pub const LOCALE_VARIABLES: &[&str] = &[
+    "LANG",
+    "LANGUAGE",
+    "LC_CTYPE",
+    "LC_NUMERIC",
+    "LC_TIME",
+    "LC_COLLATE",
+    "LC_MONETARY",
+    "LC_MESSAGES",
+    "LC_PAPER",
+    "LC_NAME",
+    "LC_ADDRESS",
+    "LC_TELEPHONE",
+    "LC_MEASUREMENT",
+    "LC_IDENTIFICATION",
+];
+
+/// Parse localed's `VARIABLE=value` assignments into a map.
+pub fn parse_locale(assignments: &[String]) -> BTreeMap<String, String> {
+    assignments
+        .iter()
+        .filter_map(|assignment| assignment.split_once('='))
+        .map(|(variable, value)| (variable.to_owned(), value.to_owned()))
+        .collect()
+}
+
+/// Convert a map of locale variables into localed's `VARIABLE=value` assignments.
+pub fn format_locale(locale: &BTreeMap<String, String>) -> Result<Vec<String>, String> {
+    locale
+        .iter()
+        .map(|(variable, value)| {
+            if !LOCALE_VARIABLES.contains(&variable.as_str()) {
+                return Err(format!("{variable} is not a locale variable"));
+            }
+
+            if value.is_empty() || value.contains(|c: char| c == '=' || c.is_whitespace()) {
+                return Err(format!("{value:?} is not a valid value for {variable}"));
+            }
+
+            Ok(format!("{variable}={value}"))
+        })
+        .collect()
+}
+
+/// Follow localed's system locale.
+pub async fn locale_task(
+    proxy: locale1::locale1Proxy<'static>,
+    tx: watch::Sender<BTreeMap<String, String>>,
+) {
+    let mut changes = proxy.receive_locale_changed().await;
+
+    if let Ok(locale) = proxy.locale().await {
+        tx.send_replace(parse_locale(&locale));
     }
 
-    config
-        .set::<XkbConfig>(COSMIC_COMP_XDG_KEY, new_config)
-        .context("Failed to update xkb_config from systemd-localed")?;
+    while let Some(change) = changes.next().await {
+        match change.get().await {
+            Ok(locale) => {
+                tx.send_replace(parse_locale(&locale));
+            }
+            Err(err) => log::error!("Failed to get the system locale from localed: {err:?}"),
+        }
+    }
+}
+
+/// Set the system locale through the system helper, which asks polkit for authorization.
+pub async fn set_locale(locale: &BTreeMap<String, String>) -> zbus::fdo::Result<()> {
+    let assignments = format_locale(locale).map_err(zbus::fdo::Error::InvalidArgs)?;
+    let assignments: Vec<&str> = assignments.iter().map(String::as_str).collect();
+
+    crate::system_helper()
+        .await?
+        .set_locale(&assignments)
+        .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::object_server::SignalEmitter;
+
+    struct MockLocale1 {
+        keyboard: X11Keyboard,
+        writes: u32,
+    }
+
+    impl MockLocale1 {
+        async fn emit_x11_changes(&self, emitter: &SignalEmitter<'_>) -> zbus::Result<()> {
+            self.x11_model_changed(emitter).await?;
+            self.x11_layout_changed(emitter).await?;
+            self.x11_variant_changed(emitter).await?;
+            self.x11_options_changed(emitter).await
+        }
+    }
+
+    #[zbus::interface(name = "org.freedesktop.locale1")]
+    impl MockLocale1 {
+        #[allow(clippy::too_many_arguments)]
+        async fn set_x11_keyboard(
+            &mut self,
+            layout: String,
+            model: String,
+            variant: String,
+            options: String,
+            _convert: bool,
+            _interactive: bool,
+            #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        ) -> zbus::fdo::Result<()> {
+            self.keyboard = X11Keyboard {
+                model,
+                layout,
+                variant,
+                options,
+            };
+            self.writes += 1;
+            self.emit_x11_changes(&emitter).await?;
+            Ok(())
+        }
+
+        #[zbus(property, name = "X11Model")]
+        fn x11_model(&self) -> String {
+            self.keyboard.model.clone()
+        }
+
+        #[zbus(property, name = "X11Layout")]
+        fn x11_layout(&self) -> String {
+            self.keyboard.layout.clone()
+        }
+
+        #[zbus(property, name = "X11Variant")]
+        fn x11_variant(&self) -> String {
+            self.keyboard.variant.clone()
+        }
+
+        #[zbus(property, name = "X11Options")]
+        fn x11_options(&self) -> String {
+            self.keyboard.options.clone()
+        }
+    }
+
+    /// Connects a client to a mock localed over a private socket.
+    async fn mock_locale1() -> (zbus::Connection, zbus::Connection) {
+        let mock = MockLocale1 {
+            keyboard: X11Keyboard {
+                model: String::from("pc105"),
+                layout: String::from("us"),
+                ..Default::default()
+            },
+            writes: 0,
+        };
+
+        crate::utils::p2p_pair(|server| server.serve_at("/org/freedesktop/locale1", mock)).await
+    }
+
+    /// Counts the bursts of changes which settle within `window`.
+    async fn bursts(
+        changes: &mut (impl tokio_stream::Stream<Item = ()> + Unpin),
+        window: Duration,
+    ) -> usize {
+        let mut debounce = Debounce::default();
+        let mut count = 0;
+        let end = tokio::time::sleep(window);
+        tokio::pin!(end);
+
+        loop {
+            tokio::select! {
+                Some(()) = changes.next() => debounce.changed(),
+                () = debounce.settled() => count += 1,
+                () = &mut end => return count,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_waits_for_quiet() {
+        let mut debounce = Debounce::default();
+        debounce.changed();
+        tokio::time::advance(SETTLE_TIME / 2).await;
+        debounce.changed();
+        tokio::time::advance(SETTLE_TIME / 2).await;
+        assert!(futures::poll!(std::pin::pin!(debounce.settled())).is_pending());
+
+        tokio::time::advance(SETTLE_TIME / 2).await;
+        assert!(futures::poll!(std::pin::pin!(debounce.settled())).is_ready());
+        assert!(futures::poll!(std::pin::pin!(debounce.settled())).is_pending());
+    }
+
+    // Time is paused, so the windows below pass only once the mock's signals have all been
+    // handled, however loaded the machine is.
+    #[tokio::test(start_paused = true)]
+    async fn layout_change_settles_once() {
+        let (client, _server) = mock_locale1().await;
+        let proxy = locale1::locale1Proxy::builder(&client)
+            .build()
+            .await
+            .unwrap();
+
+        let mut changes = x11_keyboard_changes(&proxy).await;
+        // Initial values, which the property streams may yield.
+        bursts(&mut changes, SETTLE_TIME * 2).await;
+
+        let keyboard = X11Keyboard {
+            model: String::from("pc105"),
+            layout: String::from("us,de"),
+            variant: String::from(",nodeadkeys"),
+            options: String::from("grp:alt_shift_toggle"),
+        };
+        keyboard.write(&proxy).await.unwrap();
+
+        assert_eq!(bursts(&mut changes, SETTLE_TIME * 4).await, 1);
+        assert_eq!(X11Keyboard::read(&proxy).await.unwrap(), keyboard);
+    }
+
+    #[tokio::test]
+    async fn own_writes_are_not_echoed() {
+        let (client, server) = mock_locale1().await;
+        let proxy = locale1::locale1Proxy::builder(&client)
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+            .unwrap();
+        let mock = server
+            .object_server()
+            .interface::<_, MockLocale1>("/org/freedesktop/locale1")
+            .await
+            .unwrap();
+
+        let mut synced = None;
+        let keyboard = X11Keyboard {
+            model: String::from("pc105"),
+            layout: String::from("de"),
+            ..Default::default()
+        };
+
+        // COSMIC's layout is written once, and reading it back from localed is not a change.
+        assert!(
+            push_x11_keyboard(&proxy, &mut synced, keyboard.clone())
+                .await
+                .unwrap()
+        );
+        assert_eq!(pull_x11_keyboard(&proxy, &mut synced).await.unwrap(), None);
+        assert!(
+            !push_x11_keyboard(&proxy, &mut synced, keyboard.clone())
+                .await
+                .unwrap()
+        );
+        assert_eq!(mock.get().await.writes, 1);
+
+        // A layout set by another tool is synced, and not written back to localed.
+        let external = X11Keyboard {
+            layout: String::from("fr"),
+            ..keyboard
+        };
+        mock.get_mut().await.keyboard = external.clone();
+
+        assert_eq!(
+            pull_x11_keyboard(&proxy, &mut synced).await.unwrap(),
+            Some(external.clone())
+        );
+        assert!(
+            !push_x11_keyboard(&proxy, &mut synced, external)
+                .await
+                .unwrap()
+        );
+        assert_eq!(mock.get().await.writes, 1);
+    }
+
+    #[test]
+    fn locale_round_trip() {
+        let assignments = vec![
+            "LANG=en_US.UTF-8".to_owned(),
+            "LC_TIME=de_DE.UTF-8".to_owned(),
+        ];
+
+        let locale = parse_locale(&assignments);
+        assert_eq!(locale["LANG"], "en_US.UTF-8");
+        assert_eq!(locale["LC_TIME"], "de_DE.UTF-8");
+        assert_eq!(format_locale(&locale).unwrap(), assignments);
+
+        let invalid = BTreeMap::from([("PATH".to_owned(), "/bin".to_owned())]);
+        assert!(format_locale(&invalid).is_err());
+
+        let invalid = BTreeMap::from([("LANG".to_owned(), "en_US UTF-8".to_owned())]);
+        assert!(format_locale(&invalid).is_err());
+    }
+}
@@ -1,18 +1,47 @@
 use std::collections::BTreeMap;
-use std::io;
 use std::path::Path;
-use std::rc::Rc;
-use std::time::Duration;
+use std::sync::OnceLock;
 
+use cosmic_settings_daemon_config::CosmicSettingsDaemonConfig;
 use futures::{Stream, StreamExt};
+use geonames::Database;
 pub use geonames::GeoPosition;
-use notify::{PollWatcher, RecursiveMode, Watcher};
+use tokio_stream::wrappers::ReceiverStream;
+use zbus::zvariant::OwnedObjectPath;
 
 static GEODATA: &[u8] = include_bytes!("../data/timezone-geodata.bitcode-v0-6");
 
+/// The geonames city database, as installed under the XDG data dirs.
+const CITIES_FILE: &str = "cosmic-settings-daemon/geonames-cities.bitcode-v0-6";
+
+/// Positions of timezones and cities to resolve locations with.
+#[derive(Debug, Default)]
+pub struct Geodata {
+    /// The largest city nearest each timezone.
+    pub timezones: BTreeMap<String, GeoPosition>,
+    /// The installed city database, which is empty if it is missing.
+    pub cities: Database,
+}
+
+/// The geodata, decoded on first use.
+pub fn geodata() -> &'static Geodata {
+    static GEODATA: OnceLock<Geodata> = OnceLock::new();
+    GEODATA.get_or_init(|| Geodata {
+        timezones: decode_geodata(),
+        cities: load_cities(),
+    })
+}
+
+/// [`geodata`] read off the async runtime, as it reads the city database from disk.
+pub async fn load_geodata() -> &'static Geodata {
+    tokio::task::spawn_blocking(geodata)
+        .await
+        .unwrap_or_else(|_| geodata())
+}
+
 /// Decodes the embedded geodata containing the largest cities nearest each timezone.
 pub fn decode_geodata() -> BTreeMap<String, GeoPosition> {
-    match geonames::bitcode::decode(GEODATA) {
+    match geonames::decode_timezone_positions(GEODATA) {
         Ok(ok) => ok,
         Err(err) => {
             log::error!("failed to decode timezone geodata: {}", err);
@@ -21,54 +50,437 @@ pub fn decode_geodata() -> BTreeMap<String, GeoPosition> {
     }
 }
 
-/// Get a stream of timezone updates backed by a poll watcher.
-pub fn receive_timezones() -> (PollWatcher, impl Stream<Item = io::Result<String>>) {
-    let (tx, rx) = tokio::sync::mpsc::channel(1);
+/// Reads the city database from the first XDG data dir which has one.
+fn load_cities() -> Database {
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| String::from("/usr/local/share:/usr/share"));
 
-    let timezone_path: Rc<Path> = Rc::from(Path::new("/etc/localtime"));
+    for dir in data_dirs.split(':').filter(|dir| !dir.is_empty()) {
+        let path = Path::new(dir).join(CITIES_FILE);
+        match std::fs::read(&path) {
+            Ok(data) => match Database::decode(&data) {
+                Ok(cities) => return cities,
+                Err(err) => log::error!("failed to decode {}: {err}", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => log::error!("failed to read {}: {err}", path.display()),
+        }
+    }
 
-    futures::executor::block_on(async {
-        _ = tx.send(()).await;
-    });
+    log::warn!(
+        "no geonames city database found in {data_dirs}; timezones will not be suggested and cities cannot be looked up"
+    );
+    Database::default()
+}
 
-    let event_handler = move |result: notify::Result<notify::Event>| {
-        if matches!(
-            result.map(|event| event.kind),
-            Ok(notify::EventKind::Modify(_))
-        ) {
-            futures::executor::block_on(async {
-                _ = tx.send(()).await;
-            })
+/// The timezone of the city nearest to a position.
+///
+/// The largest city of a neighbouring timezone may well be closer than that of the position's
+/// own timezone, so this needs the city database rather than the timezone geodata.
+pub fn nearest_timezone(cities: &Database, position: &GeoPosition) -> Option<String> {
+    cities
+        .nearest(position.latitude, position.longitude)
+        .map(|city| city.timezone.clone())
+}
+
+/// Where the coordinates used for sunrise and sunset times came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationSource {
+    /// Latitude and longitude set in the daemon config.
+    Manual,
+    /// A city picked from the geonames database in the daemon config.
+    City,
+    /// The location reported by GeoClue.
+    GeoClue,
+    /// The largest city in the system timezone.
+    Timezone,
+}
+
+impl LocationSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Manual => "manual",
+            Self::City => "city",
+            Self::GeoClue => "geoclue",
+            Self::Timezone => "timezone",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub source: LocationSource,
+}
+
+/// Choose the coordinates to use, preferring a manual location, then the configured city, then
+/// GeoClue, and finally the largest city in the current timezone.
+pub fn resolve_location(
+    config: &CosmicSettingsDaemonConfig,
+    geodata: &Geodata,
+    geoclue: Option<&GeoPosition>,
+    timezone: Option<&str>,
+) -> Option<Location> {
+    match config.manual_location {
+        Some(manual) if manual.is_valid() => {
+            return Some(Location {
+                latitude: manual.latitude,
+                longitude: manual.longitude,
+                source: LocationSource::Manual,
+            });
         }
+        Some(manual) => log::error!(
+            "ignoring manual location {}, {} outside of ±90° latitude and ±180° longitude",
+            manual.latitude,
+            manual.longitude
+        ),
+        None => (),
+    }
+
+    if let Some(id) = config.location_city {
+        match geodata.cities.get(id) {
+            Some(city) => {
+                return Some(Location {
+                    latitude: city.position.latitude,
+                    longitude: city.position.longitude,
+                    source: LocationSource::City,
+                });
+            }
+            None => log::error!("no city with geonames id {id} in the city database"),
+        }
+    }
+
+    if let Some(position) = geoclue {
+        return Some(Location {
+            latitude: position.latitude,
+            longitude: position.longitude,
+            source: LocationSource::GeoClue,
+        });
+    }
+
+    let timezone = timezone?;
+    let Some(position) = geodata.timezones.get(timezone) else {
+        log::error!("no matching geodata for {timezone}");
+        return None;
     };
 
-    // NOTE: Uses a poll watcher because inotify watchers automatically follow symlinks to files.
-    // We should switch back to an INotifyWatcher the moment the notify crate supports disabling symlink follows.
-    let mut watcher = notify::PollWatcher::new(
-        event_handler,
-        notify::Config::default()
-            .with_follow_symlinks(false) // NOTE: Does not currently do anything
-            .with_poll_interval(Duration::from_secs(1)),
-    )
-    .unwrap();
-
-    _ = watcher.watch(&timezone_path, RecursiveMode::NonRecursive);
-
-    let stream = tokio_stream::wrappers::ReceiverStream::new(rx).then(move |_| {
-        let timezone_path = timezone_path.clone();
-        async move {
-            let mut zoneinfo_path = timezone_path.read_link()?;
-
-            // The zoneinfo path may require resolving twice to get the correct geoname timezone.
-            if let Ok(path) = zoneinfo_path.read_link() {
-                zoneinfo_path = path;
+    Some(Location {
+        latitude: position.latitude,
+        longitude: position.longitude,
+        source: LocationSource::Timezone,
+    })
+}
+
+/// GClueAccuracyLevel for city-level accuracy.
+const GEOCLUE_ACCURACY_CITY: u32 = 4;
+
+/// ID of the daemon's desktop file, which GeoClue looks up to authorize and name the client.
+pub const DESKTOP_ID: &str = "com.system76.CosmicSettingsDaemon";
+
+#[zbus::proxy(
+    default_service = "org.freedesktop.GeoClue2",
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoClueManager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    default_service = "org.freedesktop.GeoClue2",
+    interface = "org.freedesktop.GeoClue2.Client"
+)]
+trait GeoClueClient {
+    fn start(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn set_desktop_id(&self, desktop_id: &str) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn location(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn location_updated(&self, old: OwnedObjectPath, new: OwnedObjectPath) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    default_service = "org.freedesktop.GeoClue2",
+    interface = "org.freedesktop.GeoClue2.Location"
+)]
+trait GeoClueLocation {
+    #[zbus(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+}
+
+/// A GeoClue client locating the device at city accuracy.
+pub struct GeoClue {
+    client: GeoClueClientProxy<'static>,
+}
+
+impl GeoClue {
+    /// Registers a client with GeoClue and starts it. Fails if GeoClue is not running or the
+    /// user has denied location access.
+    pub async fn start(conn: &zbus::Connection) -> zbus::Result<Self> {
+        let manager = GeoClueManagerProxy::builder(conn)
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await?;
+
+        let client_path = manager.get_client().await?;
+        let client = GeoClueClientProxy::builder(conn)
+            .path(client_path)?
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await?;
+
+        client.set_desktop_id(DESKTOP_ID).await?;
+        client
+            .set_requested_accuracy_level(GEOCLUE_ACCURACY_CITY)
+            .await?;
+        client.start().await?;
+
+        Ok(Self { client })
+    }
+
+    /// The most recent location fix, if GeoClue has one.
+    pub async fn position(&self) -> zbus::Result<Option<GeoPosition>> {
+        let path = self.client.location().await?;
+        read_geoclue_position(self.client.inner().connection(), path).await
+    }
+
+    /// A stream of location fixes as GeoClue updates them.
+    pub async fn receive_positions(&self) -> zbus::Result<impl Stream<Item = GeoPosition>> {
+        let conn = self.client.inner().connection().clone();
+        let stream = self.client.receive_location_updated().await?;
+
+        Ok(stream.filter_map(move |signal| {
+            let conn = conn.clone();
+            async move {
+                let args = signal.args().ok()?;
+                read_geoclue_position(&conn, args.new).await.ok().flatten()
+            }
+        }))
+    }
+}
+
+async fn read_geoclue_position(
+    conn: &zbus::Connection,
+    path: OwnedObjectPath,
+) -> zbus::Result<Option<GeoPosition>> {
+    if path.as_str() == "/" {
+        return Ok(None);
+    }
+
+    let location = GeoClueLocationProxy::builder(conn)
+        .path(path)?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await?;
+
+    Ok(Some(GeoPosition {
+        latitude: location.latitude().await?,
+        longitude: location.longitude().await?,
+    }))
+}
+
+pub struct GeoClueWatcher {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for GeoClueWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Get a stream of positions from GeoClue. The stream ends if GeoClue is unavailable or refuses
+/// to locate the device, so that the caller can fall back to the timezone.
+pub fn watch_geoclue() -> (GeoClueWatcher, ReceiverStream<GeoPosition>) {
+    watch_geoclue_on(crate::utils::zbus_system_connection())
+}
+
+/// [`watch_geoclue`] on the bus given by `connect`.
+fn watch_geoclue_on(
+    connect: impl Future<Output = Option<zbus::Connection>> + 'static,
+) -> (GeoClueWatcher, ReceiverStream<GeoPosition>) {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+
+    let task = tokio::task::spawn_local(async move {
+        let Some(conn) = connect.await else {
+            return;
+        };
+
+        let geoclue = match GeoClue::start(&conn).await {
+            Ok(geoclue) => geoclue,
+            Err(err) => {
+                log::warn!("GeoClue location unavailable, using the timezone instead: {err}");
+                return;
+            }
+        };
+
+        let positions = match geoclue.receive_positions().await {
+            Ok(positions) => positions,
+            Err(err) => {
+                log::warn!("Failed to subscribe to GeoClue location updates: {err}");
+                return;
+            }
+        };
+        futures::pin_mut!(positions);
+
+        if let Ok(Some(position)) = geoclue.position().await
+            && tx.send(position).await.is_err()
+        {
+            return;
+        }
+
+        while let Some(position) = positions.next().await {
+            if tx.send(position).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    (GeoClueWatcher { task }, ReceiverStream::new(rx))
+}
+
+const LOCALTIME: &str = "/etc/localtime";
+
+pub struct TimezoneWatcher {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for TimezoneWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Get a stream of timezone updates from timedate1, beginning with the current timezone.
+///
+/// The `/etc/localtime` symlink is read only for the initial timezone when timedate1 is
+/// unavailable. Relinking it by hand without timedated is not picked up, since watching the link
+/// would mean watching all of `/etc`: inotify follows symlinks, and relinking replaces the link.
+pub fn receive_timezones() -> (TimezoneWatcher, ReceiverStream<String>) {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+
+    let task = tokio::task::spawn_local(async move {
+        let timedate = match crate::utils::zbus_system_connection().await {
+            Some(conn) => timedate_proxies(&conn)
+                .await
+                .inspect_err(|err| log::warn!("Failed to connect to timedate1: {err}"))
+                .ok(),
+            None => None,
+        };
+
+        let (proxy, mut changes) = match timedate {
+            Some((proxy, changes)) => (Some(proxy), Some(changes)),
+            None => (None, None),
+        };
+
+        let initial = match &proxy {
+            Some(proxy) => proxy.timezone().await.ok(),
+            None => None,
+        };
+
+        let mut current = None;
+        let mut timezone = initial.filter(|tz| !tz.is_empty()).or_else(read_localtime);
+
+        loop {
+            if let Some(timezone) = timezone.take()
+                && current.as_ref() != Some(&timezone)
+            {
+                current = Some(timezone.clone());
+                if tx.send(timezone).await.is_err() {
+                    return;
+                }
             }
 
-            Ok(timezone_from_path(&zoneinfo_path))
+            timezone = tokio::select! {
+                _ = tx.closed() => return,
+
+                signal = async {
+                    match changes.as_mut() {
+                        Some(changes) => changes.next().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let Some(signal) = signal else {
+                        changes = None;
+                        continue;
+                    };
+
+                    let Ok(args) = signal.args() else {
+                        continue;
+                    };
+
+                    if args.interface_name != "org.freedesktop.timedate1" {
+                        continue;
+                    }
+
+                    if let Some(value) = args.changed_properties.get("Timezone") {
+                        value.downcast_ref::<&str>().ok().map(str::to_owned)
+                    } else if args.invalidated_properties.contains(&"Timezone")
+                        && let Some(proxy) = &proxy
+                    {
+                        proxy.timezone().await.ok()
+                    } else {
+                        None
+                    }
+                }
+            };
         }
     });
 
-    (watcher, stream)
+    (TimezoneWatcher { task }, ReceiverStream::new(rx))
+}
+
+async fn timedate_proxies(
+    conn: &zbus::Connection,
+) -> zbus::Result<(
+    crate::time::Timedate1Proxy<'static>,
+    zbus::fdo::PropertiesChangedStream,
+)> {
+    let proxy = crate::time::Timedate1Proxy::builder(conn)
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await?;
+
+    let changes = zbus::fdo::PropertiesProxy::builder(conn)
+        .destination("org.freedesktop.timedate1")?
+        .path("/org/freedesktop/timedate1")?
+        .build()
+        .await?
+        .receive_properties_changed()
+        .await?;
+
+    Ok((proxy, changes))
+}
+
+/// Read the timezone from the `/etc/localtime` symlink.
+fn read_localtime() -> Option<String> {
+    let mut zoneinfo_path = match Path::new(LOCALTIME).read_link() {
+        Ok(path) => path,
+        Err(err) => {
+            log::error!("Failed to read the {LOCALTIME} symlink: {err}");
+            return None;
+        }
+    };
+
+    // The zoneinfo path may require resolving twice to get the correct geoname timezone.
+    if let Ok(path) = zoneinfo_path.read_link() {
+        zoneinfo_path = path;
+    }
+
+    Some(timezone_from_path(&zoneinfo_path))
 }
 
 /// Get timezone from a zoneinfo path
@@ -90,7 +502,297 @@ fn timezone_from_path(path: &Path) -> String {
 
 #[cfg(test)]
 mod tests {
+    use super::{GeoPosition, Geodata, LocationSource};
+    use cosmic_settings_daemon_config::{CosmicSettingsDaemonConfig, ManualLocation, Twilight};
+    use futures::StreamExt;
+    use geonames::{City, Database};
+    use std::collections::BTreeMap;
     use std::path::Path;
+    use zbus::object_server::ObjectServer;
+    use zbus::zvariant::OwnedObjectPath;
+
+    const CLIENT_PATH: &str = "/org/freedesktop/GeoClue2/Client/1";
+    const LOCATION_PATH: &str = "/org/freedesktop/GeoClue2/Client/1/Location/1";
+
+    struct MockManager {
+        refuse: bool,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.GeoClue2.Manager")]
+    impl MockManager {
+        async fn get_client(
+            &self,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> zbus::fdo::Result<OwnedObjectPath> {
+            let client = MockClient {
+                refuse: self.refuse,
+                started: false,
+                desktop_id: String::new(),
+                requested_accuracy_level: 0,
+            };
+            server.at(CLIENT_PATH, client).await?;
+            Ok(OwnedObjectPath::try_from(CLIENT_PATH).unwrap())
+        }
+    }
+
+    struct MockClient {
+        refuse: bool,
+        started: bool,
+        desktop_id: String,
+        requested_accuracy_level: u32,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.GeoClue2.Client")]
+    impl MockClient {
+        async fn start(
+            &mut self,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> zbus::fdo::Result<()> {
+            if self.refuse {
+                return Err(zbus::fdo::Error::AccessDenied(
+                    "location access disabled".to_owned(),
+                ));
+            }
+
+            // Tromsø, Norway
+            let location = MockLocation {
+                latitude: 69.65,
+                longitude: 18.96,
+            };
+            server.at(LOCATION_PATH, location).await?;
+            self.started = true;
+            Ok(())
+        }
+
+        #[zbus(property)]
+        fn desktop_id(&self) -> String {
+            self.desktop_id.clone()
+        }
+
+        #[zbus(property)]
+        fn set_desktop_id(&mut self, desktop_id: String) {
+            self.desktop_id = desktop_id;
+        }
+
+        #[zbus(property)]
+        fn requested_accuracy_level(&self) -> u32 {
+            self.requested_accuracy_level
+        }
+
+        #[zbus(property)]
+        fn set_requested_accuracy_level(&mut self, level: u32) {
+            self.requested_accuracy_level = level;
+        }
+
+        #[zbus(property)]
+        fn location(&self) -> OwnedObjectPath {
+            let path = if self.started { LOCATION_PATH } else { "/" };
+            OwnedObjectPath::try_from(path).unwrap()
+        }
+    }
+
+    struct MockLocation {
+        latitude: f64,
+        longitude: f64,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.GeoClue2.Location")]
+    impl MockLocation {
+        #[zbus(property)]
+        fn latitude(&self) -> f64 {
+            self.latitude
+        }
+
+        #[zbus(property)]
+        fn longitude(&self) -> f64 {
+            self.longitude
+        }
+    }
+
+    /// Connects a client to a mock GeoClue service over a private socket.
+    async fn mock_geoclue(refuse: bool) -> (zbus::Connection, zbus::Connection) {
+        crate::utils::p2p_pair(|server| {
+            server.serve_at("/org/freedesktop/GeoClue2/Manager", MockManager { refuse })
+        })
+        .await
+    }
+
+    fn geodata() -> Geodata {
+        Geodata {
+            timezones: BTreeMap::from([(
+                String::from("Europe/Oslo"),
+                GeoPosition {
+                    latitude: 59.91,
+                    longitude: 10.75,
+                },
+            )]),
+            cities: Database::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn geoclue_location_feeds_schedule() {
+        let (client, server) = mock_geoclue(false).await;
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                // As the theme watcher follows GeoClue and schedules the theme from its location.
+                let (_watcher, mut positions) = super::watch_geoclue_on(async { Some(client) });
+                let position = positions.next().await.unwrap();
+
+                let mock_client = server
+                    .object_server()
+                    .interface::<_, MockClient>(CLIENT_PATH)
+                    .await
+                    .unwrap();
+                assert_eq!(mock_client.get().await.requested_accuracy_level, 4);
+                assert_eq!(mock_client.get().await.desktop_id, super::DESKTOP_ID);
+
+                let config = CosmicSettingsDaemonConfig::default();
+                let location = super::resolve_location(
+                    &config,
+                    &geodata(),
+                    Some(&position),
+                    Some("Europe/Oslo"),
+                )
+                .unwrap();
+                assert_eq!(location.source, LocationSource::GeoClue);
+                assert_eq!((location.latitude, location.longitude), (69.65, 18.96));
+
+                let sunrise_sunset =
+                    crate::theme::sunrise_sunset_at(&location, Twilight::default()).unwrap();
+                assert!(sunrise_sunset.is_dark().is_ok());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn geoclue_refusal_falls_back_to_timezone() {
+        let (client, _server) = mock_geoclue(true).await;
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let (_watcher, mut positions) = super::watch_geoclue_on(async { Some(client) });
+                assert!(positions.next().await.is_none());
+
+                let config = CosmicSettingsDaemonConfig::default();
+                let location =
+                    super::resolve_location(&config, &geodata(), None, Some("Europe/Oslo"))
+                        .unwrap();
+                assert_eq!(location.source, LocationSource::Timezone);
+                assert_eq!((location.latitude, location.longitude), (59.91, 10.75));
+            })
+            .await;
+    }
+
+    #[test]
+    fn nearest_timezone_to_position() {
+        let city = |id, name: &str, timezone: &str, latitude, longitude| City {
+            id,
+            name: name.to_owned(),
+            ascii_name: name.to_owned(),
+            country_code: String::new(),
+            admin1: String::new(),
+            population: 0,
+            timezone: timezone.to_owned(),
+            position: GeoPosition {
+                latitude,
+                longitude,
+            },
+        };
+        let cities = Database::new(vec![
+            city(6167865, "Toronto", "America/Toronto", 43.70, -79.42),
+            city(4990729, "Detroit", "America/Detroit", 42.33, -83.05),
+            city(4463523, "Dearborn", "America/Detroit", 42.32, -83.18),
+        ]);
+
+        // Ann Arbor, Michigan
+        let position = GeoPosition {
+            latitude: 42.28,
+            longitude: -83.74,
+        };
+        assert_eq!(
+            super::nearest_timezone(&cities, &position).as_deref(),
+            Some("America/Detroit")
+        );
+
+        // Oshawa, Ontario
+        let position = GeoPosition {
+            latitude: 43.90,
+            longitude: -78.85,
+        };
+        assert_eq!(
+            super::nearest_timezone(&cities, &position).as_deref(),
+            Some("America/Toronto")
+        );
+        assert_eq!(
+            super::nearest_timezone(&Database::default(), &position),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_location_priority() {
+        let geodata = Geodata {
+            timezones: BTreeMap::from([(
+                String::from("America/Denver"),
+                GeoPosition {
+                    latitude: 39.74,
+                    longitude: -104.98,
+                },
+            )]),
+            cities: Database::new(vec![City {
+                id: 3143244,
+                name: String::from("Oslo"),
+                ascii_name: String::from("Oslo"),
+                country_code: String::from("NO"),
+                admin1: String::from("Oslo"),
+                population: 580000,
+                timezone: String::from("Europe/Oslo"),
+                position: GeoPosition {
+                    latitude: 59.91,
+                    longitude: 10.75,
+                },
+            }]),
+        };
+
+        let mut config = CosmicSettingsDaemonConfig::default();
+        let location =
+            super::resolve_location(&config, &geodata, None, Some("America/Denver")).unwrap();
+        assert_eq!(location.source, LocationSource::Timezone);
+        assert_eq!(location.latitude, 39.74);
+
+        config.location_city = Some(3143244);
+        let location =
+            super::resolve_location(&config, &geodata, None, Some("America/Denver")).unwrap();
+        assert_eq!(location.source, LocationSource::City);
+        assert_eq!(location.longitude, 10.75);
+
+        config.manual_location = Some(ManualLocation {
+            latitude: 69.65,
+            longitude: 18.96,
+        });
+        let location = super::resolve_location(&config, &geodata, None, None).unwrap();
+        assert_eq!(location.source, LocationSource::Manual);
+        assert_eq!(location.latitude, 69.65);
+
+        // Coordinates out of range are ignored.
+        config.manual_location = Some(ManualLocation {
+            latitude: 96.0,
+            longitude: 18.96,
+        });
+        let location =
+            super::resolve_location(&config, &geodata, None, Some("America/Denver")).unwrap();
+        assert_eq!(location.source, LocationSource::City);
+
+        // A city missing from the database falls back to the timezone.
+        config.manual_location = None;
+        config.location_city = Some(1);
+        let location =
+            super::resolve_location(&config, &geodata, None, Some("America/Denver")).unwrap();
+        assert_eq!(location.source, LocationSource::Timezone);
+        assert!(super::resolve_location(&config, &geodata, None, None).is_none());
+    }
 
     #[test]
     fn timezone_from_path() {
@@ -3,11 +3,12 @@
 
 use brightness_device::BrightnessDevice;
 use cosmic_config::ConfigGet;
-use futures::lock::Mutex;
+use cosmic_settings_system_helper::HelperProxy;
+use cosmic_settings_varlink_server::config as config_store;
 use logind_session::LogindSessionProxy;
 use notify::event::ModifyKind;
 use notify::{EventKind, Watcher};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
@@ -24,17 +25,20 @@ use tokio::task;
 use tokio_stream::StreamExt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use zbus::names::{MemberName, UniqueName, WellKnownName};
+use zbus::names::{UniqueName, WellKnownName};
 use zbus::object_server::SignalEmitter;
 use zbus::zvariant::ObjectPath;
 use zbus::{Connection, MatchRule, MessageStream};
+mod accessibility;
 mod battery;
 mod brightness_device;
+mod debug;
 mod greeter;
 mod locale;
 mod location;
 mod logind_session;
 mod pipewire;
+mod sandbox;
 mod theme;
 mod time;
 mod utils;
@@ -48,38 +52,207 @@ mod wayland;
 pub static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 static DBUS_NAME: &str = "com.system76.CosmicSettingsDaemon";
+
+static SYSTEM_HELPER: tokio::sync::OnceCell<HelperProxy<'static>> =
+    tokio::sync::OnceCell::const_new();
+
+/// The system helper, which performs privileged operations after checking the caller's
+/// authorization with polkit. The bus starts it on first use.
+pub(crate) async fn system_helper() -> zbus::Result<&'static HelperProxy<'static>> {
+    SYSTEM_HELPER
+        .get_or_try_init(|| async {
+            let connection = Connection::system().await?;
+            HelperProxy::new(&connection).await
+        })
+        .await
+}
 static DBUS_PATH: &str = "/com/system76/CosmicSettingsDaemon";
 
 struct SettingsDaemon {
     /// Directly access varlink daemon methods within the DBus daemon.
     varlink_daemon: Arc<tokio::sync::Mutex<cosmic_settings_varlink_server::DaemonInner>>,
     logind_session: Option<LogindSessionProxy<'static>>,
-    a11y_session: Option<Mutex<cosmic_dbus_a11y::StatusProxy<'static>>>,
+    /// System locale variables, as set in localed.
+    locale: tokio::sync::watch::Receiver<BTreeMap<String, String>>,
+    /// Screen reader state, applied by `accessibility::screen_reader_task`.
+    screen_reader: tokio::sync::watch::Sender<accessibility::ScreenReaderState>,
     display_brightness_device: BrightnessDevice,
-    #[allow(clippy::type_complexity)]
-    watched_configs: Arc<
-        RwLock<HashMap<(String, u64), (Connection, ObjectPath<'static>, WellKnownName<'static>)>>,
-    >,
-    #[allow(clippy::type_complexity)]
-    watched_states: Arc<
-        RwLock<HashMap<(String, u64), (Connection, ObjectPath<'static>, WellKnownName<'static>)>>,
-    >,
-    wayland_sender: calloop::channel::Sender<wayland::Cmd>,
+    watched: WatchedEntries,
+    wayland_sender: wayland::Sender,
+    /// Index of the active input source, published by the Wayland thread.
+    input_source: tokio::sync::watch::Receiver<u32>,
+    /// Whether the Wayland thread is connected to the compositor.
+    wayland_available: tokio::sync::watch::Receiver<bool>,
+    location: tokio::sync::watch::Receiver<Option<location::Location>>,
+    /// Last known high contrast state, to signal only when it changes.
+    high_contrast: AtomicBool,
+    /// Reduce motion state, kept in step with CosmicTk.
+    reduce_motion: AtomicBool,
+    /// Large text state, kept in step with CosmicTk.
+    large_text: AtomicBool,
 }
 
+/// A served config or state entry, and the clients watching it.
+struct WatchedConfig {
+    conn: Connection,
+    path: ObjectPath<'static>,
+    name: WellKnownName<'static>,
+    /// Whether `conn` is the entry's own connection, as with the original watch API, rather
+    /// than the daemon's main connection.
+    dedicated: bool,
+    /// Unique names of the clients that asked to watch the entry.
+    watchers: HashSet<UniqueName<'static>>,
+}
+
+type WatchedConfigs = Arc<RwLock<HashMap<(String, u64), WatchedConfig>>>;
+
+/// Watched entries, by kind and by whether they are served on their own connection (V1) or
+/// on the main connection (V2).
+#[derive(Clone, Default)]
+struct WatchedEntries {
+    configs: WatchedConfigs,
+    states: WatchedConfigs,
+    configs_v2: WatchedConfigs,
+    states_v2: WatchedConfigs,
+}
+
+impl WatchedEntries {
+    fn get(&self, kind: config_store::Kind, dedicated: bool) -> &WatchedConfigs {
+        match (kind, dedicated) {
+            (config_store::Kind::Config, true) => &self.configs,
+            (config_store::Kind::State, true) => &self.states,
+            (config_store::Kind::Config, false) => &self.configs_v2,
+            (config_store::Kind::State, false) => &self.states_v2,
+        }
+    }
+
+    fn all(&self) -> [(&'static str, &WatchedConfigs); 4] {
+        [
+            ("Config", &self.configs),
+            ("State", &self.states),
+            ("ConfigV2", &self.configs_v2),
+            ("StateV2", &self.states_v2),
+        ]
+    }
+}
+
+/// Stops counting `watcher` as a watcher of every entry, and stops serving entries that no one
+/// watches anymore.
+async fn release_watcher(configs: &WatchedConfigs, watcher: &UniqueName<'_>) {
+    let unwatched: Vec<_> = {
+        let mut configs = configs.write().await;
+        for watched in configs.values_mut() {
+            watched.watchers.remove(watcher.as_str());
+        }
+        configs
+            .extract_if(|_, watched| watched.watchers.is_empty())
+            .collect()
+    };
+
+    for ((id, version), watched) in unwatched {
+        log::debug!("{id} v{version} is no longer watched");
+        let result = if watched.dedicated {
+            watched.conn.close().await
+        } else {
+            watched
+                .conn
+                .object_server()
+                .remove::<Config, _>(&watched.path)
+                .await
+                .map(|_| ())
+        };
+        if let Err(err) = result {
+            log::error!("Failed to stop serving {id} v{version}: {err}");
+        }
+    }
+}
+
+/// Signals the watchers of an entry that `key` changed.
+async fn emit_config_changed(
+    configs: &WatchedConfigs,
+    kind: config_store::Kind,
+    id: &str,
+    key: &str,
+    version: u64,
+) {
+    let read_guard = configs.read().await;
+    let Some(watched) = read_guard.get(&(id.to_owned(), version)) else {
+        return;
+    };
+    let Ok(config) = watched
+        .conn
+        .object_server()
+        .interface::<_, Config>(&watched.path)
+        .await
+    else {
+        return;
+    };
+
+    if let Err(err) = Config::changed(config.signal_emitter(), id.to_owned(), key.to_owned()).await
+    {
+        log::error!("Failed to send {kind:?} changed signal: {}", err);
+    }
+
+    // Read the value once here rather than in every subscriber. A key removed without a system
+    // default is sent with an empty value. The value goes only to the clients watching the entry,
+    // which sandboxed clients may only do for their own ids.
+    let value = match config_store::get(kind, id, version, key) {
+        Ok(value) => value,
+        Err(config_store::Error::NoSuchKey { .. }) => String::new(),
+        Err(why) => {
+            log::error!("Failed to read changed {kind:?} key {id}/{key}: {why}");
+            return;
+        }
+    };
+    for watcher in &watched.watchers {
+        let emitter = config
+            .signal_emitter()
+            .clone()
+            .set_destination(watcher.clone().into());
+        if let Err(err) =
+            Config::changed_with_value(&emitter, id.to_owned(), key.to_owned(), value.clone()).await
+        {
+            log::error!("Failed to send {kind:?} changed signal: {}", err);
+        }
+    }
+}
+
+fn config_error(why: config_store::Error) -> zbus::fdo::Error {
+    match why {
+        config_store::Error::InvalidName { .. }
+        | config_store::Error::NoSuchKey { .. }
+        | config_store::Error::InvalidValue { .. } => {
+            zbus::fdo::Error::InvalidArgs(why.to_string())
+        }
+        config_store::Error::NoHomeDir | config_store::Error::IO { .. } => {
+            zbus::fdo::Error::IOError(why.to_string())
+        }
+    }
+}
+
+/// A cosmic-config or state id served for clients to watch and edit.
 #[derive(Debug)]
-enum Config {
-    Config,
-    State,
+struct Config {
+    kind: config_store::Kind,
+    id: String,
+    version: u64,
 }
 
 impl Config {
-    fn new_config() -> Self {
-        Self::Config
+    fn new_config(id: &str, version: u64) -> Self {
+        Self {
+            kind: config_store::Kind::Config,
+            id: id.to_owned(),
+            version,
+        }
     }
 
-    fn new_state() -> Self {
-        Self::State
+    fn new_state(id: &str, version: u64) -> Self {
+        Self {
+            kind: config_store::Kind::State,
+            id: id.to_owned(),
+            version,
+        }
     }
 }
 
@@ -87,11 +260,67 @@ impl Config {
 impl Config {
     #[zbus(signal)]
     async fn changed(emitter: &SignalEmitter<'_>, id: String, key: String) -> zbus::Result<()>;
+
+    /// Like `changed`, with the key's new RON value so subscribers need not read it back. The
+    /// value is empty when the key was removed and has no system default.
+    #[zbus(signal)]
+    async fn changed_with_value(
+        emitter: &SignalEmitter<'_>,
+        id: String,
+        key: String,
+        value: String,
+    ) -> zbus::Result<()>;
+
+    /// The RON value of a key, or its system default.
+    ///
+    /// Sandboxed clients may only read and write their own app's ids.
+    async fn get(
+        &self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        key: &str,
+    ) -> zbus::fdo::Result<String> {
+        sandbox::authorize_config_access(conn, &header, &self.id).await?;
+        config_store::get(self.kind, &self.id, self.version, key).map_err(config_error)
+    }
+
+    /// Sets a key to a RON value.
+    async fn set(
+        &self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        key: &str,
+        value: &str,
+    ) -> zbus::fdo::Result<()> {
+        sandbox::authorize_config_access(conn, &header, &self.id).await?;
+        config_store::set(self.kind, &self.id, self.version, key, value).map_err(config_error)
+    }
+
+    /// Keys that have a value or a system default.
+    async fn list(
+        &self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+    ) -> zbus::fdo::Result<Vec<String>> {
+        sandbox::authorize_config_access(conn, &header, &self.id).await?;
+        config_store::list(self.kind, &self.id, self.version).map_err(config_error)
+    }
+
+    /// RON values of every key.
+    async fn get_all(
+        &self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+    ) -> zbus::fdo::Result<BTreeMap<String, String>> {
+        sandbox::authorize_config_access(conn, &header, &self.id).await?;
+        config_store::get_all(self.kind, &self.id, self.version).map_err(config_error)
+    }
 }
 
 impl Config {
-    fn path(&self, id: &str, version: u64) -> ObjectPath<'static> {
-        let cfg_type = if matches!(self, Config::State) {
+    fn path(&self) -> ObjectPath<'static> {
+        let (id, version) = (&self.id, self.version);
+        let cfg_type = if self.kind == config_store::Kind::State {
             "State"
         } else {
             "Config"
@@ -112,8 +341,9 @@ impl Config {
         })
     }
 
-    fn name(&self, id: &str, version: u64) -> WellKnownName<'static> {
-        let cfg_type = if matches!(self, Config::State) {
+    fn name(&self) -> WellKnownName<'static> {
+        let (id, version) = (&self.id, self.version);
+        let cfg_type = if self.kind == config_store::Kind::State {
             "State"
         } else {
             "Config"
@@ -189,6 +419,144 @@ impl SettingsDaemon {
         let _ = self.wayland_sender.send(wayland::Cmd::InputSourceSwitch);
     }
 
+    /// Switch to the previous input source.
+    async fn previous_input_source(&self) {
+        let _ = self.wayland_sender.send(wayland::Cmd::PreviousInputSource);
+    }
+
+    /// Switch to the input source at `index` in the xkb config's layouts.
+    async fn set_input_source(&self, index: u32) -> zbus::fdo::Result<()> {
+        let count = wayland::load_input_sources().await.len();
+        if index as usize >= count {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "input source {index} does not exist, there are {count}"
+            )));
+        }
+
+        if !self
+            .wayland_sender
+            .send(wayland::Cmd::SetInputSource(index))
+        {
+            return Err(zbus::fdo::Error::Failed(
+                "input sources are unavailable without a Wayland compositor".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The system locale: `LANG`, `LANGUAGE` and `LC_*` variables mapped to their values.
+    #[zbus(property)]
+    async fn locale(&self) -> BTreeMap<String, String> {
+        self.locale.borrow().clone()
+    }
+
+    /// Replace the system locale through localed. Variables which are left out are unset.
+    async fn set_locale(&self, locale: BTreeMap<String, String>) -> zbus::fdo::Result<()> {
+        locale::set_locale(&locale).await
+    }
+
+    /// Start charging batteries below `start` percent, and stop charging them at `end` percent.
+    async fn set_charge_thresholds(&self, start: u8, end: u8) -> zbus::fdo::Result<()> {
+        system_helper()
+            .await?
+            .set_charge_thresholds(start, end)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether keyboard layout features are available, which requires a Wayland compositor.
+    #[zbus(property)]
+    async fn wayland_available(&self) -> bool {
+        *self.wayland_available.borrow()
+    }
+
+    /// The layout code, variant and description of the active input source.
+    #[zbus(property)]
+    async fn active_input_source(&self) -> (String, String, String) {
+        let index = *self.input_source.borrow() as usize;
+        let source = wayland::load_input_sources()
+            .await
+            .into_iter()
+            .nth(index)
+            .unwrap_or_default();
+        (source.layout, source.variant, source.description)
+    }
+
+    /// Coordinates used for the automatic theme switch, and the source they came from.
+    ///
+    /// The source is one of `manual`, `city`, `geoclue` or `timezone`; or empty if no location is
+    /// known.
+    #[zbus(property)]
+    async fn location(&self) -> (f64, f64, String) {
+        match self.location.borrow().as_ref() {
+            Some(location) => (
+                location.latitude,
+                location.longitude,
+                location.source.as_str().to_owned(),
+            ),
+            None => (0.0, 0.0, String::new()),
+        }
+    }
+
+    /// Whether the light and dark themes use their high contrast palettes.
+    #[zbus(property)]
+    async fn high_contrast(&self) -> bool {
+        accessibility::high_contrast()
+    }
+
+    #[zbus(property)]
+    async fn set_high_contrast(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        accessibility::set_high_contrast(enabled).map_err(|why| {
+            zbus::fdo::Error::Failed(format!("failed to set high contrast: {why}"))
+        })?;
+        self.high_contrast.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether non-essential animations are disabled.
+    #[zbus(property)]
+    async fn reduce_motion(&self) -> bool {
+        self.reduce_motion.load(Ordering::Relaxed)
+    }
+
+    #[zbus(property)]
+    async fn set_reduce_motion(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        accessibility::set_reduce_motion(enabled).map_err(|why| {
+            zbus::fdo::Error::Failed(format!("failed to set reduce motion: {why}"))
+        })?;
+        self.reduce_motion.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether text is enlarged.
+    #[zbus(property)]
+    async fn large_text(&self) -> bool {
+        self.large_text.load(Ordering::Relaxed)
+    }
+
+    #[zbus(property)]
+    async fn set_large_text(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        accessibility::set_large_text(enabled)
+            .map_err(|why| zbus::fdo::Error::Failed(format!("failed to set large text: {why}")))?;
+        self.large_text.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the screen reader is enabled.
+    ///
+    /// Changes requested before the accessibility bus is available are applied once it appears.
+    #[zbus(property)]
+    async fn screen_reader_enabled(&self) -> bool {
+        self.screen_reader.borrow().desired()
+    }
+
+    #[zbus(property)]
+    async fn set_screen_reader_enabled(&self, enabled: bool) {
+        self.screen_reader
+            .send_modify(|state| state.pending = Some(enabled));
+    }
+
     #[zbus(property)]
     async fn max_display_brightness(&self) -> i32 {
         self.display_brightness_device.max_brightness()
@@ -247,28 +615,10 @@ impl SettingsDaemon {
 
     async fn decrease_keyboard_brightness(&self) {}
 
-    async fn screen_reader(&mut self) {
-        if let Some(a11y) = self.a11y_session.as_ref() {
-            let guard = a11y.lock().await;
-            let Ok(current_state) = guard.is_enabled().await else {
-                log::error!("Failed to toggle screen reader. Could not read current state.");
-                return;
-            };
-            let new_state = !current_state;
-            if let Err(err) = guard.set_is_enabled(new_state).await {
-                log::error!(
-                    "Failed to toggle screen reader. Could not apply current state. {err:?}"
-                );
-                return;
-            }
-            if let Err(err) = guard.set_screen_reader_enabled(new_state).await {
-                log::error!(
-                    "Failed to toggle screen reader. Could not apply current state. {err:?}"
-                );
-            }
-        } else {
-            log::error!("Failed to toggle screen reader.")
-        }
+    async fn screen_reader(&self) {
+        self.screen_reader.send_modify(|state| {
+            state.pending = Some(!state.desired());
+        });
     }
 
     async fn volume_up(&self) {
@@ -297,51 +647,133 @@ impl SettingsDaemon {
         }
     }
 
+    /// Serves the config on a new connection with its own bus name.
+    ///
+    /// Each watched config costs a bus connection; new clients should use `WatchConfigV2`,
+    /// which serves the same object on the daemon's own connection.
     async fn watch_config(
         &mut self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
         id: &str,
         version: u64,
     ) -> zbus::fdo::Result<(ObjectPath<'static>, WellKnownName<'static>)> {
+        sandbox::authorize_config_access(conn, &header, id).await?;
         // create a new config, return the path and add it to our hashmap
-        Self::watch_config_inner(self, Config::new_config(), id, version).await
+        let config = Config::new_config(id, version);
+        Self::watch_config_inner(self, conn, &header, config, true).await
     }
 
+    /// Serves the state on a new connection with its own bus name.
+    ///
+    /// Each watched state costs a bus connection; new clients should use `WatchStateV2`,
+    /// which serves the same object on the daemon's own connection.
     async fn watch_state(
         &mut self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
         id: &str,
         version: u64,
     ) -> zbus::fdo::Result<(ObjectPath<'static>, WellKnownName<'static>)> {
-        Self::watch_config_inner(self, Config::new_state(), id, version).await
+        sandbox::authorize_config_access(conn, &header, id).await?;
+        let config = Config::new_state(id, version);
+        Self::watch_config_inner(self, conn, &header, config, true).await
+    }
+
+    /// Serves the config at the returned path on the daemon's connection, until every client
+    /// that asked for it has left the bus.
+    #[zbus(name = "WatchConfigV2")]
+    async fn watch_config_v2(
+        &mut self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        id: &str,
+        version: u64,
+    ) -> zbus::fdo::Result<ObjectPath<'static>> {
+        sandbox::authorize_config_access(conn, &header, id).await?;
+        let config = Config::new_config(id, version);
+        let (path, _) = Self::watch_config_inner(self, conn, &header, config, false).await?;
+        Ok(path)
+    }
+
+    /// Serves the state at the returned path on the daemon's connection, until every client
+    /// that asked for it has left the bus.
+    #[zbus(name = "WatchStateV2")]
+    async fn watch_state_v2(
+        &mut self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        id: &str,
+        version: u64,
+    ) -> zbus::fdo::Result<ObjectPath<'static>> {
+        sandbox::authorize_config_access(conn, &header, id).await?;
+        let config = Config::new_state(id, version);
+        let (path, _) = Self::watch_config_inner(self, conn, &header, config, false).await?;
+        Ok(path)
     }
 }
 
 impl SettingsDaemon {
+    /// Serves `config` on its own connection if `dedicated`, otherwise on the daemon's
+    /// connection `conn`, and counts the caller as a watcher.
     async fn watch_config_inner(
         &mut self,
+        conn: &Connection,
+        header: &zbus::message::Header<'_>,
         config: Config,
-        id: &str,
-        version: u64,
+        dedicated: bool,
     ) -> zbus::fdo::Result<(ObjectPath<'static>, WellKnownName<'static>)> {
-        let configs = match config {
-            Config::Config => &self.watched_configs,
-            Config::State => &self.watched_states,
+        let Some(sender) = header.sender().map(|s| s.to_owned()) else {
+            return Err(zbus::fdo::Error::Failed("unknown sender".into()));
+        };
+        let configs = self.watched.get(config.kind, dedicated);
+        let key = (config.id.clone(), config.version);
+        let watched = configs.write().await.get_mut(&key).map(|watched| {
+            watched.watchers.insert(sender.clone());
+            (watched.path.clone(), watched.name.clone())
+        });
+        let (path, name) = match watched {
+            Some(served) => served,
+            None => {
+                let path = config.path();
+                let (config_conn, name) = if dedicated {
+                    let name = config.name();
+                    let config_conn = zbus::connection::Builder::session()?
+                        .name(name.as_str())?
+                        .serve_at(path.to_owned(), config)?
+                        .build()
+                        .await?;
+                    (config_conn, name)
+                } else {
+                    conn.object_server().at(path.to_owned(), config).await?;
+                    (
+                        conn.clone(),
+                        WellKnownName::from_static_str_unchecked(DBUS_NAME),
+                    )
+                };
+
+                configs.write().await.insert(
+                    key,
+                    WatchedConfig {
+                        conn: config_conn,
+                        path: path.clone(),
+                        name: name.clone(),
+                        dedicated,
+                        watchers: HashSet::from([sender.clone()]),
+                    },
+                );
+                (path, name)
+            }
         };
-        if let Some((_, path, name)) = configs.read().await.get(&(id.to_string(), version)) {
-            return Ok((path.to_owned(), name.to_owned()));
+
+        // The client may have gone away before its watch was counted, in which case its
+        // NameOwnerChanged signal has already been handled.
+        let dbus = zbus::fdo::DBusProxy::new(conn).await?;
+        if !dbus.name_has_owner(sender.clone().into()).await? {
+            release_watcher(configs, &sender).await;
         }
-        let path = config.path(id, version);
-        let name = config.name(id, version);
-        let conn = zbus::connection::Builder::session()?
-            .name(name.as_str())?
-            .serve_at(path.to_owned(), config)?
-            .build()
-            .await?;
 
-        configs.write().await.insert(
-            (id.to_owned(), version),
-            (conn, path.to_owned(), name.to_owned()),
-        );
-        Ok((path.to_owned(), name.to_owned()))
+        Ok((path, name))
     }
 }
 
@@ -439,6 +871,116 @@ async fn backlight_monitor_task(
     };
 }
 
+async fn location_monitor_task(
+    mut location_rx: tokio::sync::watch::Receiver<Option<location::Location>>,
+    connection: zbus::Connection,
+) {
+    let Ok(interface) = connection
+        .object_server()
+        .interface::<_, SettingsDaemon>(DBUS_PATH)
+        .await
+    else {
+        return;
+    };
+
+    while location_rx.changed().await.is_ok() {
+        _ = interface
+            .get()
+            .await
+            .location_changed(interface.signal_emitter())
+            .await;
+    }
+}
+
+async fn wayland_monitor_task(
+    mut input_source_rx: tokio::sync::watch::Receiver<u32>,
+    mut available_rx: tokio::sync::watch::Receiver<bool>,
+    connection: zbus::Connection,
+) {
+    let Ok(interface) = connection
+        .object_server()
+        .interface::<_, SettingsDaemon>(DBUS_PATH)
+        .await
+    else {
+        return;
+    };
+
+    loop {
+        tokio::select! {
+            changed = input_source_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+
+                _ = interface
+                    .get()
+                    .await
+                    .active_input_source_changed(interface.signal_emitter())
+                    .await;
+            }
+
+            changed = available_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+
+                _ = interface
+                    .get()
+                    .await
+                    .wayland_available_changed(interface.signal_emitter())
+                    .await;
+            }
+        }
+    }
+}
+
+async fn locale_monitor_task(
+    mut locale_rx: tokio::sync::watch::Receiver<BTreeMap<String, String>>,
+    connection: zbus::Connection,
+) {
+    let Ok(interface) = connection
+        .object_server()
+        .interface::<_, SettingsDaemon>(DBUS_PATH)
+        .await
+    else {
+        return;
+    };
+
+    while locale_rx.changed().await.is_ok() {
+        _ = interface
+            .get()
+            .await
+            .locale_changed(interface.signal_emitter())
+            .await;
+    }
+}
+
+async fn screen_reader_monitor_task(
+    mut screen_reader_rx: tokio::sync::watch::Receiver<accessibility::ScreenReaderState>,
+    connection: zbus::Connection,
+) {
+    let Ok(interface) = connection
+        .object_server()
+        .interface::<_, SettingsDaemon>(DBUS_PATH)
+        .await
+    else {
+        return;
+    };
+
+    let mut enabled = screen_reader_rx.borrow_and_update().desired();
+    while screen_reader_rx.changed().await.is_ok() {
+        let desired = screen_reader_rx.borrow_and_update().desired();
+        if desired != enabled {
+            enabled = desired;
+            _ = interface
+                .get()
+                .await
+                .screen_reader_enabled_changed(interface.signal_emitter())
+                .await;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Change {
     Config(String, String, u64),
@@ -491,7 +1033,9 @@ async fn main() -> ExitCode {
         .with(log_layer)
         .init();
 
-    if let Err(err) = greeter::sync_with_greeter() {
+    let (screen_reader_tx, screen_reader_rx) = tokio::sync::watch::channel(Default::default());
+    let (a11y_tx, a11y_rx) = tokio::sync::watch::channel(Default::default());
+    if let Err(err) = greeter::sync_with_greeter(&screen_reader_tx, &a11y_tx) {
         log::error!("Failed to sync with greeter. {err:?}");
     }
 
@@ -524,13 +1068,12 @@ async fn main() -> ExitCode {
             }
             .await;
 
-            let a11y_session = async {
-                let connection = zbus::Connection::session().await?;
-                cosmic_dbus_a11y::StatusProxy::builder(&connection)
-                    .build()
-                    .await
+            let locale1 = async {
+                let connection = zbus::Connection::system().await?;
+                locale1::locale1Proxy::new(&connection).await
             }
             .await;
+
             let xdg_config = dirs::config_dir()
                 .map(|x| x.join("cosmic"))
                 .or_else(|| dirs::home_dir().map(|p| p.join(".config/cosmic")));
@@ -611,21 +1154,46 @@ async fn main() -> ExitCode {
             {
                 log::error!("Failed to watch xdg state dir: {}", err);
             }
-            let watched_configs = Arc::new(RwLock::new(HashMap::new()));
-            let watched_states = Arc::new(RwLock::new(HashMap::new()));
+            let (location_tx, location_rx) = tokio::sync::watch::channel(None);
+            let (schedule_tx, schedule_rx) = tokio::sync::watch::channel(Default::default());
+            let (time_tx, time_rx) = tokio::sync::mpsc::channel(4);
+            let (input_source_tx, input_source_rx) = tokio::sync::watch::channel(0);
+            let (locale_tx, locale_rx) = tokio::sync::watch::channel(BTreeMap::new());
+            let (wayland_available_tx, wayland_available_rx) = tokio::sync::watch::channel(false);
+            let watched = WatchedEntries::default();
             let settings_daemon = SettingsDaemon {
                 varlink_daemon: varlink_daemon_context.clone(),
                 logind_session: logind_session.ok(),
-                a11y_session: a11y_session.ok().map(Mutex::new),
+                locale: locale_rx.clone(),
+                screen_reader: screen_reader_tx.clone(),
                 display_brightness_device,
-                watched_configs: watched_configs.clone(),
-                watched_states: watched_states.clone(),
-                wayland_sender: wayland::run(),
+                watched: watched.clone(),
+                wayland_sender: wayland::run(input_source_tx, a11y_tx, wayland_available_tx),
+                input_source: input_source_rx.clone(),
+                wayland_available: wayland_available_rx.clone(),
+                location: location_rx.clone(),
+                high_contrast: AtomicBool::new(accessibility::high_contrast()),
+                reduce_motion: AtomicBool::new(accessibility::reduce_motion()),
+                large_text: AtomicBool::new(accessibility::large_text()),
             };
 
             let connection = zbus::connection::Builder::session()?
                 .name(DBUS_NAME)?
                 .serve_at(DBUS_PATH, settings_daemon)?
+                .serve_at(time::TIME_CONTEXT_PATH, time::TimeContext::default())?
+                .serve_at(
+                    debug::DEBUG_PATH,
+                    debug::DaemonDebug {
+                        watched: watched.clone(),
+                    },
+                )?
+                .serve_at(
+                    theme::THEME_SCHEDULE_PATH,
+                    theme::ThemeSchedule {
+                        state: schedule_rx,
+                        location: location_rx.clone(),
+                    },
+                )?
                 .build()
                 .await?;
 
@@ -636,11 +1204,42 @@ async fn main() -> ExitCode {
 
             tokio::task::spawn_local(battery::low_power_monitor());
 
+            let conn_clone = connection.clone();
+            let location_rx_clone = location_rx.clone();
+            task::spawn_local(async move {
+                location_monitor_task(location_rx_clone, conn_clone).await;
+            });
+
+            let conn_clone = connection.clone();
+            task::spawn_local(async move {
+                wayland_monitor_task(input_source_rx, wayland_available_rx, conn_clone).await;
+            });
+
+            if let Ok(locale1) = locale1 {
+                task::spawn_local(locale::locale_task(locale1, locale_tx));
+
+                let conn_clone = connection.clone();
+                task::spawn_local(async move {
+                    locale_monitor_task(locale_rx, conn_clone).await;
+                });
+            }
+
+            task::spawn_local(accessibility::screen_reader_task(screen_reader_tx));
+            task::spawn_local(greeter::save_compositor_a11y(a11y_rx));
+
+            let conn_clone = connection.clone();
+            task::spawn_local(async move {
+                screen_reader_monitor_task(screen_reader_rx, conn_clone).await;
+            });
+
             let conn_clone = connection.clone();
             task::spawn_local(async move {
-                if let Err(err) =
-                    watch_config_message_stream(conn_clone, watched_configs, watched_states).await
-                {
+                time::time_context_task(location_rx, time_rx, conn_clone).await;
+            });
+
+            let conn_clone = connection.clone();
+            task::spawn_local(async move {
+                if let Err(err) = watch_config_message_stream(conn_clone, watched).await {
                     log::error!("Failed to watch config message stream: {}", err);
                 }
             });
@@ -651,7 +1250,15 @@ async fn main() -> ExitCode {
                 let mut sleep = Duration::from_millis(100);
 
                 loop {
-                    if let Err(err) = watch_theme(&mut theme_rx, &mut theme_cancel_rx).await {
+                    if let Err(err) = watch_theme(
+                        &mut theme_rx,
+                        &mut theme_cancel_rx,
+                        &location_tx,
+                        &schedule_tx,
+                        &time_tx,
+                    )
+                    .await
+                    {
                         log::error!(
                             "Failed to watch theme {err:?}. Will try again in {}s",
                             sleep.as_secs()
@@ -674,16 +1281,22 @@ async fn main() -> ExitCode {
             let conn_clone = connection.clone();
             let main_fut = std::pin::pin!(async move {
                 while let Some(changes) = rx.recv().await {
-                    let Ok(settings_daemon) = conn_clone
+                    let Ok(settings_daemon_ref) = conn_clone
                         .object_server()
                         .interface::<_, SettingsDaemon>(DBUS_PATH)
                         .await
                     else {
                         continue;
                     };
-                    let settings_daemon = settings_daemon.get().await;
+                    let settings_daemon = settings_daemon_ref.get().await;
                     for c in changes {
                         if let Change::Config(id, key, version) = c {
+                            varlink_daemon_context.lock().await.config_watchers.changed(
+                                config_store::Kind::Config,
+                                &id,
+                                version,
+                                &key,
+                            );
                             if id.as_str() == cosmic_theme::THEME_MODE_ID {
                                 if let Err(err) =
                                     theme_tx.send(theme::ThemeMsg::ThemeMode(key.clone())).await
@@ -696,11 +1309,60 @@ async fn main() -> ExitCode {
                                 {
                                     log::error!("Failed to send theme toolkit update {err:?}");
                                 }
+
+                                if key == accessibility::TK_REDUCE_MOTION_KEY {
+                                    let reduce_motion = accessibility::reduce_motion();
+                                    if settings_daemon
+                                        .reduce_motion
+                                        .swap(reduce_motion, Ordering::Relaxed)
+                                        != reduce_motion
+                                    {
+                                        accessibility::sync_reduce_motion(reduce_motion);
+                                        _ = settings_daemon
+                                            .reduce_motion_changed(
+                                                settings_daemon_ref.signal_emitter(),
+                                            )
+                                            .await;
+                                    }
+                                } else if key == accessibility::TK_LARGE_TEXT_KEY {
+                                    let large_text = accessibility::large_text();
+                                    if settings_daemon
+                                        .large_text
+                                        .swap(large_text, Ordering::Relaxed)
+                                        != large_text
+                                    {
+                                        accessibility::sync_large_text(large_text);
+                                        _ = settings_daemon
+                                            .large_text_changed(
+                                                settings_daemon_ref.signal_emitter(),
+                                            )
+                                            .await;
+                                    }
+                                }
                             } else if id.as_str() == cosmic_theme::DARK_THEME_ID {
                                 if let Err(err) = theme_tx.send(theme::ThemeMsg::Theme(true)).await
                                 {
                                     log::error!("Failed to send dark theme update {err:?}");
                                 }
+
+                                let high_contrast = accessibility::high_contrast();
+                                if settings_daemon
+                                    .high_contrast
+                                    .swap(high_contrast, Ordering::Relaxed)
+                                    != high_contrast
+                                {
+                                    accessibility::sync_high_contrast(high_contrast);
+                                    if let Err(err) = greeter::save_for_greeter(|state| {
+                                        state.high_contrast = Some(high_contrast);
+                                    }) {
+                                        log::error!(
+                                            "Failed to save high contrast for the greeter: {err:?}"
+                                        );
+                                    }
+                                    _ = settings_daemon
+                                        .high_contrast_changed(settings_daemon_ref.signal_emitter())
+                                        .await;
+                                }
                             } else if id.as_str() == cosmic_theme::LIGHT_THEME_ID {
                                 if let Err(err) = theme_tx.send(theme::ThemeMsg::Theme(false)).await
                                 {
@@ -712,7 +1374,29 @@ async fn main() -> ExitCode {
                                 if let Err(err) = xkb_tx.send(()).await {
                                     log::error!("Failed to send xkb layout update: {err:?}");
                                 }
+                                _ = settings_daemon
+                                    .active_input_source_changed(
+                                        settings_daemon_ref.signal_emitter(),
+                                    )
+                                    .await;
                             } else if id.as_str() == cosmic_settings_daemon_config::NAME {
+                                if let Err(err) = theme_tx
+                                    .send(theme::ThemeMsg::DaemonConfig(key.clone()))
+                                    .await
+                                {
+                                    log::error!(
+                                        "Failed to send settings daemon config update {err:?}"
+                                    );
+                                }
+
+                                if key == wayland::INPUT_SOURCE_MEMORY_KEY {
+                                    let _ = settings_daemon.wayland_sender.send(
+                                        wayland::Cmd::SetInputSourceMemory(
+                                            wayland::input_source_memory(),
+                                        ),
+                                    );
+                                }
+
                                 let mut daemon = varlink_daemon_context.lock().await;
 
                                 let mono_sound = daemon
@@ -733,46 +1417,38 @@ async fn main() -> ExitCode {
                                     }
                                 }
                             }
-                            let read_guard = settings_daemon.watched_configs.read().await;
-                            let Some((conn, path, _)) = read_guard.get(&(id.to_string(), version))
-                            else {
-                                continue;
-                            };
-                            let Ok(config) =
-                                conn.object_server().interface::<_, Config>(path).await
-                            else {
-                                continue;
-                            };
-
-                            if let Err(err) = Config::changed(
-                                config.signal_emitter(),
-                                id.to_string(),
-                                key.to_string(),
-                            )
-                            .await
-                            {
-                                log::error!("Failed to send config changed signal: {}", err);
+                            for dedicated in [true, false] {
+                                let configs = settings_daemon
+                                    .watched
+                                    .get(config_store::Kind::Config, dedicated);
+                                emit_config_changed(
+                                    configs,
+                                    config_store::Kind::Config,
+                                    &id,
+                                    &key,
+                                    version,
+                                )
+                                .await;
                             }
                         } else if let Change::State(id, key, version) = c {
-                            let read_guard = settings_daemon.watched_states.read().await;
-                            let Some((conn, path, _)) = read_guard.get(&(id.to_string(), version))
-                            else {
-                                continue;
-                            };
-
-                            let Ok(state) = conn.object_server().interface::<_, Config>(path).await
-                            else {
-                                continue;
-                            };
-
-                            if let Err(err) = Config::changed(
-                                state.signal_emitter(),
-                                id.to_string(),
-                                key.to_string(),
-                            )
-                            .await
-                            {
-                                log::error!("Failed to send state changed signal: {}", err);
+                            varlink_daemon_context.lock().await.config_watchers.changed(
+                                config_store::Kind::State,
+                                &id,
+                                version,
+                                &key,
+                            );
+                            for dedicated in [true, false] {
+                                let configs = settings_daemon
+                                    .watched
+                                    .get(config_store::Kind::State, dedicated);
+                                emit_config_changed(
+                                    configs,
+                                    config_store::Kind::State,
+                                    &id,
+                                    &key,
+                                    version,
+                                )
+                                .await;
                             }
                         }
                     }
@@ -804,33 +1480,8 @@ async fn main() -> ExitCode {
 
 async fn watch_config_message_stream(
     conn: Connection,
-    watched_configs: Arc<
-        RwLock<HashMap<(String, u64), (Connection, ObjectPath<'static>, WellKnownName<'static>)>>,
-    >,
-    watched_states: Arc<
-        RwLock<HashMap<(String, u64), (Connection, ObjectPath<'static>, WellKnownName<'static>)>>,
-    >,
+    watched: WatchedEntries,
 ) -> zbus::Result<()> {
-    let config_rule = MatchRule::builder()
-        .msg_type(zbus::message::Type::MethodCall)
-        .member("WatchConfig")?
-        .interface("com.system76.CosmicSettingsDaemon")?
-        .build();
-    let config_stream = MessageStream::for_match_rule(config_rule, &conn, Some(100)).await?;
-
-    let mut watched_config_names: HashMap<(String, u64), HashSet<UniqueName<'static>>> =
-        HashMap::new();
-
-    let state_rule = MatchRule::builder()
-        .msg_type(zbus::message::Type::MethodCall)
-        .member("WatchState")?
-        .interface("com.system76.CosmicSettingsDaemon")?
-        .build();
-    let state_stream = MessageStream::for_match_rule(state_rule, &conn, Some(100)).await?;
-
-    let mut watched_state_names: HashMap<(String, u64), HashSet<UniqueName<'static>>> =
-        HashMap::new();
-
     let name_changed_rule = MatchRule::builder()
         .msg_type(zbus::message::Type::Signal)
         .sender("org.freedesktop.DBus")?
@@ -839,67 +1490,134 @@ async fn watch_config_message_stream(
         .arg(2, "")? // new owner is empty
         .build();
 
-    let name_changed_stream =
-        MessageStream::for_match_rule(name_changed_rule, &conn, Some(100)).await?;
-
-    let mut rx = name_changed_stream.merge(config_stream).merge(state_stream);
+    let mut rx = MessageStream::for_match_rule(name_changed_rule, &conn, Some(100)).await?;
 
     while let Some(msg) = rx.try_next().await? {
-        let header = msg.header();
-        if header.member() == Some(&MemberName::from_static_str_unchecked("NameOwnerChanged")) {
-            let Ok((name, old_owner, _)) = msg.body().deserialize::<(String, String, String)>()
-            else {
-                continue;
-            };
-            if name != old_owner {
-                continue;
-            }
-            let unique_name = UniqueName::from_str_unchecked(&old_owner).to_owned();
-            for ((k, v), is_config) in watched_config_names
-                .iter_mut()
-                .map(|a| (a, true))
-                .chain(watched_state_names.iter_mut().map(|a| (a, false)))
-                .filter(|((_, v), _)| v.contains(&unique_name))
-            {
-                v.remove(&unique_name);
-                if v.is_empty() {
-                    let mut write_guard = if is_config {
-                        watched_configs.write().await
-                    } else {
-                        watched_states.write().await
-                    };
-                    write_guard.retain(|(id, version), (_, _, _)| &k.0 != id || &k.1 != version);
-                }
-            }
-            watched_config_names.retain(|_, v| !v.is_empty());
-            watched_state_names.retain(|_, v| !v.is_empty());
-        } else if header.member() == Some(&MemberName::from_static_str_unchecked("WatchConfig")) {
-            let Some(sender) = header.sender() else {
-                continue;
-            };
+        let Ok((name, old_owner, _)) = msg.body().deserialize::<(String, String, String)>() else {
+            continue;
+        };
+        if name != old_owner {
+            continue;
+        }
+        let unique_name = UniqueName::from_str_unchecked(&old_owner);
+        for (_, configs) in watched.all() {
+            release_watcher(configs, &unique_name).await;
+        }
+    }
 
-            let Ok((id, version)) = msg.body().deserialize::<(String, u64)>() else {
-                continue;
-            };
+    Ok(())
+}
 
-            let name_set = watched_config_names
-                .entry((id.clone(), version))
-                .or_default();
-            name_set.insert(sender.to_owned());
-        } else if header.member() == Some(&MemberName::from_static_str_unchecked("WatchState")) {
-            let Some(sender) = header.sender().map(|s| s.to_owned()) else {
-                continue;
-            };
-            let Ok((id, version)) = msg.body().deserialize::<(String, u64)>() else {
-                continue;
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let name_set = watched_state_names
-                .entry((id.clone(), version))
-                .or_default();
-            name_set.insert(sender.to_owned());
-        }
+    async fn serve(
+        configs: &WatchedConfigs,
+        conn: &Connection,
+        config: Config,
+        dedicated: bool,
+        watchers: &[&'static str],
+    ) -> ObjectPath<'static> {
+        let key = (config.id.clone(), config.version);
+        let path = config.path();
+        conn.object_server().at(&path, config).await.unwrap();
+        configs.write().await.insert(
+            key,
+            WatchedConfig {
+                conn: conn.clone(),
+                path: path.clone(),
+                name: WellKnownName::from_static_str_unchecked(DBUS_NAME),
+                dedicated,
+                watchers: watchers
+                    .iter()
+                    .map(|name| UniqueName::from_static_str_unchecked(name))
+                    .collect(),
+            },
+        );
+        path
     }
 
-    Ok(())
+    async fn is_served(conn: &Connection, path: &ObjectPath<'_>) -> bool {
+        conn.object_server()
+            .interface::<_, Config>(path)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn unwatched_entries_stop_being_served() {
+        let (_client, server) = crate::utils::p2p_pair(Ok).await;
+        let configs = WatchedConfigs::default();
+        let shared = serve(
+            &configs,
+            &server,
+            Config::new_config("com.system76.Shared", 1),
+            false,
+            &[":1.1", ":1.2"],
+        )
+        .await;
+        let single = serve(
+            &configs,
+            &server,
+            Config::new_config("com.system76.Single", 1),
+            false,
+            &[":1.1"],
+        )
+        .await;
+
+        release_watcher(&configs, &UniqueName::from_static_str_unchecked(":1.1")).await;
+        assert!(is_served(&server, &shared).await);
+        assert!(!is_served(&server, &single).await);
+        assert_eq!(
+            configs.read().await[&("com.system76.Shared".to_owned(), 1)]
+                .watchers
+                .iter()
+                .map(UniqueName::as_str)
+                .collect::<Vec<_>>(),
+            [":1.2"]
+        );
+
+        release_watcher(&configs, &UniqueName::from_static_str_unchecked(":1.2")).await;
+        assert!(!is_served(&server, &shared).await);
+        assert!(configs.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unwatched_dedicated_connections_are_closed() {
+        let (client, server) = crate::utils::p2p_pair(Ok).await;
+        let configs = WatchedConfigs::default();
+        serve(
+            &configs,
+            &server,
+            Config::new_state("com.system76.Dedicated", 1),
+            true,
+            &[":1.1"],
+        )
+        .await;
+        drop(server);
+        let proxy = zbus::fdo::PeerProxy::builder(&client)
+            .destination(DBUS_NAME)
+            .unwrap()
+            .path("/")
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        // A watcher that never watched the entry changes nothing.
+        release_watcher(&configs, &UniqueName::from_static_str_unchecked(":1.9")).await;
+        assert_eq!(configs.read().await.len(), 1);
+        proxy.ping().await.unwrap();
+
+        // The client sees the entry's connection go away.
+        release_watcher(&configs, &UniqueName::from_static_str_unchecked(":1.1")).await;
+        assert!(configs.read().await.is_empty());
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), proxy.ping())
+                .await
+                .unwrap()
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,82 @@
+//! Identifies sandboxed D-Bus peers so their config access can be confined to their own ids.
+
+use std::io;
+
+/// The Flatpak app id of a process, or `None` if it is not sandboxed.
+pub fn flatpak_app_id(pid: u32) -> io::Result<Option<String>> {
+    let info = match std::fs::read_to_string(format!("/proc/{pid}/root/.flatpak-info")) {
+        Ok(info) => info,
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(why) => return Err(why),
+    };
+
+    parse_flatpak_info(&info)
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no app name in .flatpak-info"))
+}
+
+fn parse_flatpak_info(info: &str) -> Option<String> {
+    let mut in_application = false;
+    for line in info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if in_application && let Some(name) = line.strip_prefix("name=") {
+            return Some(name.trim().to_owned()).filter(|name| !name.is_empty());
+        }
+    }
+
+    None
+}
+
+/// Whether an app may access the config id, which must be its own id or below it.
+pub fn owns_config(app_id: &str, id: &str) -> bool {
+    id.strip_prefix(app_id)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Rejects reads, watches and writes by sandboxed peers of other apps' config ids. Unsandboxed
+/// peers run as the user, who can already read and write every config file.
+pub async fn authorize_config_access(
+    conn: &zbus::Connection,
+    header: &zbus::message::Header<'_>,
+    id: &str,
+) -> zbus::fdo::Result<()> {
+    let sender = header
+        .sender()
+        .ok_or_else(|| zbus::fdo::Error::AccessDenied("message has no sender".to_owned()))?;
+    let pid = zbus::fdo::DBusProxy::new(conn)
+        .await?
+        .get_connection_unix_process_id(sender.clone().into())
+        .await?;
+
+    match flatpak_app_id(pid) {
+        Ok(None) => Ok(()),
+        Ok(Some(app_id)) if owns_config(&app_id, id) => Ok(()),
+        Ok(Some(app_id)) => Err(zbus::fdo::Error::AccessDenied(format!(
+            "{app_id} may not access {id}"
+        ))),
+        Err(why) => Err(zbus::fdo::Error::AccessDenied(format!(
+            "cannot identify sender {sender}: {why}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_app_name() {
+        let info = "[Application]\nname=org.example.App\nruntime=runtime/org.gnome.Platform\n\n[Instance]\nname=other\n";
+        assert_eq!(parse_flatpak_info(info).as_deref(), Some("org.example.App"));
+        assert_eq!(parse_flatpak_info("[Instance]\nname=other\n"), None);
+    }
+
+    #[test]
+    fn apps_own_their_ids() {
+        assert!(owns_config("org.example.App", "org.example.App"));
+        assert!(owns_config("org.example.App", "org.example.App.Window"));
+        assert!(!owns_config("org.example.App", "org.example.AppTwo"));
+        assert!(!owns_config("org.example.App", "com.system76.CosmicComp"));
+    }
+}
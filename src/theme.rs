@@ -1,30 +1,39 @@
 // TODO later...
 // If configured to, run scripts in XDG_DATA_DIR/dark-mode.d/ or XDG_DATA_DIR/light-mode.d/
 // when the theme is set to auto-export color palette, write to gtk3 / gtk4 / kde / ... css files
-// read config file for lat/long
 
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::OnceLock;
 
 use anyhow::bail;
-use chrono::{DateTime, Days, Local};
+use chrono::{DateTime, Days, Local, NaiveDate, TimeZone, Utc};
 use cosmic::config::CosmicTk;
 use cosmic::theme::CosmicTheme;
-use cosmic_config::CosmicConfigEntry;
+use cosmic_config::{ConfigGet, ConfigSet, CosmicConfigEntry};
+use cosmic_settings_daemon_config::{
+    AppliedFlatpakThemeOverride, CosmicSettingsDaemonConfig, CosmicSettingsDaemonState,
+    FlatpakThemeOverride, ThemeVariant, Twilight,
+};
 use cosmic_theme::{Theme, ThemeMode};
 
-use geonames::GeoPosition;
-use sunrise::{Coordinates, SolarDay, SolarEvent};
+use crate::location::{GeoPosition, Location};
+use crate::time::TimeUpdate;
+use sunrise::{Coordinates, DawnType, SolarDay, SolarEvent};
 use tokio::time::Instant;
 use tokio_stream::StreamExt;
 
-#[derive(Debug)]
-pub struct SunriseSunset {
-    last_update: DateTime<Local>,
-    sunrise: Instant,
-    sunset: Instant,
+/// Sunrise and sunset on one local day, which the theme watcher switches at.
+#[derive(Clone, Debug)]
+pub struct SunriseSunset<Tz: TimeZone = Local> {
+    tz: Tz,
+    /// The local day the times were calculated for.
+    date: NaiveDate,
+    daylight: Daylight,
+    sunrise: DateTime<Utc>,
+    sunset: DateTime<Utc>,
     lat: f64,
     long: f64,
+    twilight: Twilight,
 }
 
 pub enum ThemeMsg {
@@ -32,99 +41,409 @@ pub enum ThemeMsg {
     /// true if dark
     Theme(bool),
     Tk(String),
+    DaemonConfig(String),
 }
 
-impl SunriseSunset {
-    pub fn new(lat: f64, long: f64, t: Option<DateTime<Local>>) -> anyhow::Result<Self> {
-        let (system_t, instant_t, t) = if let Some(t) = t {
-            let system_t = SystemTime::from(t);
-            let system_now = SystemTime::now();
-            let delta_t = system_t.duration_since(system_now)?;
-            let instant_t = Instant::now()
-                .checked_add(delta_t)
-                .ok_or(anyhow::anyhow!("Could not calculate instant"))?;
-
-            (system_t, instant_t, t)
+/// Daylight on a single day at a given place.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Daylight {
+    /// The sun rises and sets, or crosses the twilight altitude, at these times.
+    Normal {
+        sunrise: DateTime<Utc>,
+        sunset: DateTime<Utc>,
+    },
+    /// Midnight sun; it does not get dark all day.
+    PolarDay,
+    /// Polar night; it does not get light all day.
+    PolarNight,
+}
+
+/// Daylight on the given day at the given coordinates, where it is dark once the sun is
+/// further below the horizon than the twilight allows.
+pub fn daylight(
+    lat: f64,
+    long: f64,
+    date: NaiveDate,
+    twilight: Twilight,
+) -> anyhow::Result<Daylight> {
+    let Some(coords) = Coordinates::new(lat, long) else {
+        bail!("Invalid coordinates {lat}, {long}");
+    };
+
+    let (altitude, rise, set) = match twilight {
+        Twilight::Sunset => (-0.833, SolarEvent::Sunrise, SolarEvent::Sunset),
+        Twilight::Civil => (
+            -6.0,
+            SolarEvent::Dawn(DawnType::Civil),
+            SolarEvent::Dusk(DawnType::Civil),
+        ),
+        Twilight::Nautical => (
+            -12.0,
+            SolarEvent::Dawn(DawnType::Nautical),
+            SolarEvent::Dusk(DawnType::Nautical),
+        ),
+    };
+
+    // The sun is highest as it crosses the meridian at noon, and lowest at midnight. If both
+    // are on the same side of the twilight altitude, there is no sunrise or sunset to ask for.
+    let declination = solar_declination(date);
+    let noon_altitude = 90.0 - (lat - declination).abs();
+    let midnight_altitude = (lat + declination).abs() - 90.0;
+    if midnight_altitude > altitude {
+        return Ok(Daylight::PolarDay);
+    } else if noon_altitude < altitude {
+        return Ok(Daylight::PolarNight);
+    }
+
+    let solar_day = SolarDay::new(coords, date);
+    let sunrise = solar_day.event_time(rise);
+    let sunset = solar_day.event_time(set);
+
+    // Within a fraction of a degree of polar day or night, the approximate declination may
+    // disagree with the solar calculation, which then gives times away from the given day.
+    let noon = date.and_hms_opt(12, 0, 0).unwrap_or_default().and_utc()
+        - chrono::Duration::seconds((long * 240.0) as i64);
+    let on_day = |time: DateTime<Utc>| (time - noon).num_hours().abs() <= 24;
+    if sunrise >= sunset || !on_day(sunrise) || !on_day(sunset) {
+        return Ok(if noon_altitude - altitude > altitude - midnight_altitude {
+            Daylight::PolarDay
         } else {
-            (SystemTime::now(), Instant::now(), Local::now())
-        };
+            Daylight::PolarNight
+        });
+    }
 
-        let naive_date = t.date_naive();
-        let coords = Coordinates::new(lat, long).unwrap();
-        let solar_day = SolarDay::new(coords, naive_date);
-        let sunrise = solar_day.event_time(SolarEvent::Sunrise).timestamp();
-        let sunset = solar_day.event_time(SolarEvent::Sunset).timestamp();
+    Ok(Daylight::Normal { sunrise, sunset })
+}
 
-        let Some(sunrise) =
-            UNIX_EPOCH.checked_add(std::time::Duration::from_secs(u64::try_from(sunrise)?))
-        else {
-            bail!("Failed to calculate sunrise time");
-        };
+/// Declination of the sun in degrees at noon UTC on the given day.
+fn solar_declination(date: NaiveDate) -> f64 {
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap_or_default();
+    let days = (date - j2000).num_days() as f64;
+
+    let mean_anomaly = (357.5291 + 0.98560028 * days)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center = 1.9148 * mean_anomaly.sin()
+        + 0.02 * (2.0 * mean_anomaly).sin()
+        + 0.0003 * (3.0 * mean_anomaly).sin();
+    let ecliptic_longitude = (mean_anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+
+    (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin())
+        .asin()
+        .to_degrees()
+}
 
-        let Some(sunset) =
-            UNIX_EPOCH.checked_add(std::time::Duration::from_secs(u64::try_from(sunset)?))
-        else {
-            bail!("Failed to calculate sunset time");
-        };
+/// The start of the given day in a timezone.
+fn local_midnight<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> anyhow::Result<DateTime<Utc>> {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .ok_or(anyhow::anyhow!("Failed to calculate the start of {date}"))
+}
 
-        let st_to_instant = |now: SystemTime, st: SystemTime| -> anyhow::Result<Instant> {
-            Ok(if st > now {
-                instant_t
-                    .checked_add(st.duration_since(now)?)
-                    .ok_or(anyhow::anyhow!("Failed to convert system time to instant"))?
-            } else {
-                instant_t
-                    .checked_sub(now.duration_since(st)?)
-                    .ok_or(anyhow::anyhow!("Failed to convert system time to instant"))?
-            })
+/// The monotonic instant of a wall-clock time, for sleeping until it.
+fn instant_at(time: DateTime<Utc>) -> Instant {
+    let now = Instant::now();
+    (time - Utc::now())
+        .to_std()
+        .map_or(now, |delay| now + delay)
+}
+
+impl SunriseSunset {
+    pub fn new(
+        lat: f64,
+        long: f64,
+        t: Option<DateTime<Local>>,
+        twilight: Twilight,
+    ) -> anyhow::Result<Self> {
+        let date = t.unwrap_or_else(Local::now).date_naive();
+        Self::on(Local, lat, long, date, twilight)
+    }
+
+    /// Recalculate for today if the day has changed since the last calculation, such as when
+    /// waking at midnight in polar day or night.
+    pub fn refresh(&mut self) -> anyhow::Result<()> {
+        self.refresh_at(Utc::now())
+    }
+
+    pub fn is_dark(&self) -> anyhow::Result<bool> {
+        if self.date != Local::now().date_naive() {
+            bail!("SunriseSunset out of date");
+        }
+
+        Ok(self.is_dark_at(Utc::now()))
+    }
+
+    pub fn next(&self) -> anyhow::Result<Instant> {
+        self.next_at(Utc::now())
+            .map(instant_at)
+            .ok_or(anyhow::anyhow!(
+                "SunriseSunset instants have already passed..."
+            ))
+    }
+
+    pub fn update_next(&mut self) -> anyhow::Result<Instant> {
+        self.update_next_at(Utc::now()).map(instant_at)
+    }
+}
+
+impl<Tz: TimeZone> SunriseSunset<Tz> {
+    /// Sunrise and sunset on the given day in a timezone.
+    pub fn on(
+        tz: Tz,
+        lat: f64,
+        long: f64,
+        date: NaiveDate,
+        twilight: Twilight,
+    ) -> anyhow::Result<Self> {
+        let daylight = daylight(lat, long, date, twilight)?;
+        let (sunrise, sunset) = match daylight {
+            Daylight::Normal { sunrise, sunset } => (sunrise, sunset),
+
+            // Without a sunrise or sunset, the next day starts at midnight, when the theme
+            // watcher wakes to check it.
+            Daylight::PolarDay => (
+                local_midnight(&tz, date)?,
+                local_midnight(&tz, date + Days::new(1))?,
+            ),
+            Daylight::PolarNight => {
+                let midnight = local_midnight(&tz, date + Days::new(1))?;
+                (midnight, midnight)
+            }
         };
 
         Ok(Self {
-            last_update: t,
-            sunrise: st_to_instant(system_t, sunrise)?,
-            sunset: st_to_instant(system_t, sunset)?,
+            tz,
+            date,
+            daylight,
+            sunrise,
+            sunset,
             lat,
             long,
+            twilight,
         })
     }
 
-    pub fn is_dark(&self) -> anyhow::Result<bool> {
-        if self.last_update.date_naive() != Local::now().date_naive() {
-            bail!("SunriseSunset out of date");
+    pub fn daylight(&self) -> Daylight {
+        self.daylight
+    }
+
+    /// Recalculate for the local day of `now`, if that is not the day last calculated.
+    pub fn refresh_at(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let today = now.with_timezone(&self.tz).date_naive();
+        if today != self.date {
+            *self = Self::on(self.tz.clone(), self.lat, self.long, today, self.twilight)?;
         }
+        Ok(())
+    }
 
-        let now = Instant::now();
-        Ok(now < self.sunrise || now >= self.sunset)
+    pub fn is_dark_at(&self, now: DateTime<Utc>) -> bool {
+        now < self.sunrise || now >= self.sunset
     }
 
-    pub fn next(&self) -> anyhow::Result<Instant> {
-        let now = Instant::now();
-        if self.sunrise.checked_duration_since(now).is_some() {
-            Ok(self.sunrise)
-        } else if self.sunset.checked_duration_since(now).is_some() {
-            Ok(self.sunset)
+    /// The next actual sunrise or sunset after `now`, looking ahead up to a year through polar
+    /// day or night.
+    pub fn next_transition_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.date.iter_days().take(366).find_map(|date| {
+            match daylight(self.lat, self.long, date, self.twilight).ok()? {
+                Daylight::Normal { sunrise, sunset } => {
+                    [sunrise, sunset].into_iter().find(|&time| now < time)
+                }
+                Daylight::PolarDay | Daylight::PolarNight => None,
+            }
+        })
+    }
+
+    fn next_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        [self.sunrise, self.sunset]
+            .into_iter()
+            .find(|&time| now < time)
+    }
+
+    /// The next sunrise or sunset after `now`, or the next midnight in polar day or night;
+    /// moving on to the following day once this day's have passed.
+    pub fn update_next_at(&mut self, now: DateTime<Utc>) -> anyhow::Result<DateTime<Utc>> {
+        if let Some(next) = self.next_at(now) {
+            return Ok(next);
+        }
+
+        // Skip to today if days went by without waking, such as during a suspend.
+        let next_day = (self.date + Days::new(1)).max(now.with_timezone(&self.tz).date_naive());
+        for date in [next_day, next_day + Days::new(1)] {
+            *self = Self::on(self.tz.clone(), self.lat, self.long, date, self.twilight)?;
+            if let Some(next) = self.next_at(now) {
+                return Ok(next);
+            }
+        }
+
+        bail!("Failed to calculate next date for theme auto-switch.");
+    }
+}
+
+/// Sunrise and sunset today at a location, which the theme watcher switches at.
+pub fn sunrise_sunset_at(location: &Location, twilight: Twilight) -> Option<SunriseSunset> {
+    SunriseSunset::new(location.latitude, location.longitude, None, twilight)
+        .inspect_err(|err| {
+            log::error!("Failed to calculate sunrise and sunset at {location:?}: {err:?}")
+        })
+        .ok()
+}
+
+/// Sunrise and sunset on the local day of `at` in a timezone, whether it is dark then, and when
+/// the theme watcher would next wake to switch; as computed by the theme watcher.
+fn simulate<Tz: TimeZone>(
+    tz: Tz,
+    lat: f64,
+    long: f64,
+    twilight: Twilight,
+    at: DateTime<Utc>,
+) -> anyhow::Result<(i64, i64, bool, i64)> {
+    let date = at.with_timezone(&tz).date_naive();
+    let mut sunrise_sunset = SunriseSunset::on(tz, lat, long, date, twilight)?;
+
+    let (sunrise, sunset) = match sunrise_sunset.daylight() {
+        Daylight::Normal { sunrise, sunset } => (sunrise.timestamp(), sunset.timestamp()),
+        Daylight::PolarDay | Daylight::PolarNight => (0, 0),
+    };
+    let is_dark = sunrise_sunset.is_dark_at(at);
+    let next = sunrise_sunset.update_next_at(at)?;
+
+    Ok((sunrise, sunset, is_dark, next.timestamp()))
+}
+
+pub static THEME_SCHEDULE_PATH: &str = "/com/system76/CosmicSettingsDaemon/ThemeSchedule";
+
+/// The theme mode as last applied by the theme watcher.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThemeScheduleState {
+    pub auto_switch: bool,
+    pub is_dark: bool,
+    /// The mode was changed by hand and the schedule resumes at the next sunrise or sunset.
+    pub override_until_next: bool,
+    pub twilight: Twilight,
+}
+
+impl ThemeScheduleState {
+    /// Why the theme is in its current mode.
+    fn reason(self) -> &'static str {
+        if !self.auto_switch {
+            "manual"
+        } else if self.override_until_next {
+            "overridden"
         } else {
-            bail!("SunriseSunset instants have already passed...");
+            "scheduled"
         }
     }
+}
 
-    pub fn update_next(&mut self) -> anyhow::Result<Instant> {
-        match self.next() {
-            Ok(i) => Ok(i),
-            Err(_) => {
-                let Some(tomorrow) = self.last_update.checked_add_days(Days::new(1)) else {
-                    bail!("Failed to calculate next date for theme auto-switch.");
-                };
-                *self = Self::new(self.lat, self.long, Some(tomorrow))?;
-                self.next()
+/// Preview of the automatic theme switch, for settings and for testing the schedule.
+pub struct ThemeSchedule {
+    pub state: tokio::sync::watch::Receiver<ThemeScheduleState>,
+    pub location: tokio::sync::watch::Receiver<Option<Location>>,
+}
+
+#[zbus::interface(name = "com.system76.CosmicSettingsDaemon.ThemeSchedule")]
+impl ThemeSchedule {
+    /// The local date, sunrise and sunset in seconds since the Unix epoch for the next `days`
+    /// days starting today, whether a manual change overrides the schedule until the next
+    /// sunrise or sunset, and the reason for the current mode: `manual`, `scheduled` or
+    /// `overridden`.
+    ///
+    /// Each day also says whether the sun rises and sets (`normal`), stays up (`polar-day`) or
+    /// stays down (`polar-night`), in which case its sunrise and sunset are 0. The schedule is
+    /// empty if no location is known.
+    #[zbus(out_args("schedule", "override_until_next", "reason"))]
+    async fn schedule(&self, days: u32) -> (Vec<(String, i64, i64, String)>, bool, String) {
+        let state = *self.state.borrow();
+        let schedule = match self.location.borrow().as_ref() {
+            Some(location) => {
+                schedule_days(location, state.twilight, Local::now().date_naive(), days)
             }
+            None => Vec::new(),
+        };
+
+        (
+            schedule,
+            state.override_until_next,
+            state.reason().to_owned(),
+        )
+    }
+
+    /// Compute the schedule at the given time, coordinates and timezone without changing the
+    /// theme, the same way the theme watcher does.
+    ///
+    /// Returns sunrise and sunset for the day of `timestamp` in `timezone`, such as
+    /// `Europe/Oslo`; whether it is dark then; and when the theme watcher would next wake to
+    /// switch, which is at midnight during polar day and night. All times are in seconds since
+    /// the Unix epoch, with sunrise and sunset 0 if the sun does not rise or set that day.
+    #[zbus(out_args("sunrise", "sunset", "is_dark", "next_transition"))]
+    async fn simulate(
+        &self,
+        timestamp: i64,
+        latitude: f64,
+        longitude: f64,
+        timezone: &str,
+    ) -> zbus::fdo::Result<(i64, i64, bool, i64)> {
+        let Some(at) = DateTime::from_timestamp(timestamp, 0) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "invalid timestamp {timestamp}"
+            )));
+        };
+
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "invalid coordinates {latitude}, {longitude}"
+            )));
         }
+
+        let Ok(tz) = timezone.parse::<chrono_tz::Tz>() else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "unknown timezone {timezone}"
+            )));
+        };
+
+        let twilight = self.state.borrow().twilight;
+        simulate(tz, latitude, longitude, twilight, at)
+            .map_err(|err| zbus::fdo::Error::Failed(format!("{err:?}")))
     }
 }
 
+/// Sunrise and sunset for each day, keyed by the ISO 8601 date, and whether the sun rises and
+/// sets (`normal`), stays up (`polar-day`) or stays down (`polar-night`).
+fn schedule_days(
+    location: &Location,
+    twilight: Twilight,
+    start: NaiveDate,
+    days: u32,
+) -> Vec<(String, i64, i64, String)> {
+    // Enough for a year's preview, without letting callers request unbounded work.
+    const MAX_DAYS: u32 = 366;
+
+    start
+        .iter_days()
+        .take(days.min(MAX_DAYS) as usize)
+        .filter_map(|date| {
+            let (sunrise, sunset, kind) =
+                match daylight(location.latitude, location.longitude, date, twilight).ok()? {
+                    Daylight::Normal { sunrise, sunset } => {
+                        (sunrise.timestamp(), sunset.timestamp(), "normal")
+                    }
+                    Daylight::PolarDay => (0, 0, "polar-day"),
+                    Daylight::PolarNight => (0, 0, "polar-night"),
+                };
+            Some((date.to_string(), sunrise, sunset, kind.to_owned()))
+        })
+        .collect()
+}
+
 pub async fn watch_theme(
     theme_mode_rx: &mut tokio::sync::mpsc::Receiver<ThemeMsg>,
     theme_cancel_rx: &mut tokio::sync::mpsc::Receiver<()>,
+    location_tx: &tokio::sync::watch::Sender<Option<Location>>,
+    schedule_tx: &tokio::sync::watch::Sender<ThemeScheduleState>,
+    time_tx: &tokio::sync::mpsc::Sender<TimeUpdate>,
 ) -> anyhow::Result<()> {
     let mut override_until_next = false;
 
@@ -156,6 +475,22 @@ pub async fn watch_theme(
         }
     };
 
+    let daemon_helper = CosmicSettingsDaemonConfig::config()?;
+    let mut daemon_config = match CosmicSettingsDaemonConfig::get_entry(&daemon_helper) {
+        Ok(c) => c,
+        Err((errs, c)) => {
+            for why in errs {
+                if let cosmic_config::Error::GetKey(_, err) = &why
+                    && err.kind() == std::io::ErrorKind::NotFound
+                {
+                    continue;
+                }
+                log::error!("{why}");
+            }
+            c
+        }
+    };
+
     set_gnome_button_layout(tk.show_maximize, tk.show_minimize);
     set_gnome_icon_theme(tk.icon_theme.clone());
 
@@ -198,19 +533,76 @@ pub async fn watch_theme(
         log::error!("Failed to reset the cosmic theme exports. {err:?}");
     }
 
-    // TODO allow preference for config file instead?
-    let geodata = crate::location::decode_geodata();
+    let geodata = crate::location::load_geodata().await;
     let (_location_handle, location_updates) = crate::location::receive_timezones();
     futures::pin_mut!(location_updates);
 
     let (_time_handle, time_changes) = crate::time::watch_time_changes().await?;
     futures::pin_mut!(time_changes);
 
-    // Track the most-recent coordinates so we can recompute sunrise/sunset after suspend or
+    // Track the most-recent location so we can recompute sunrise/sunset after suspend or
     // wall-clock changes.
-    let mut coords: Option<(f64, f64)> = None;
+    let mut timezone: Option<String> = None;
+    let mut location: Option<Location> = None;
+    let mut pending_location: Option<Location> = None;
+    let mut geoclue_position: Option<GeoPosition> = None;
+    let mut geoclue = daemon_config
+        .automatic_location
+        .then(crate::location::watch_geoclue);
     let mut sunrise_sunset: Option<SunriseSunset> = None;
     loop {
+        if let Some(new_location) = pending_location.take() {
+            sunrise_sunset = sunrise_sunset_at(&new_location, daemon_config.twilight);
+
+            _ = time_tx.send(TimeUpdate::Sun(sunrise_sunset.clone())).await;
+            location_tx.send_replace(Some(new_location.clone()));
+            location = Some(new_location);
+
+            if theme_mode.auto_switch
+                && !override_until_next
+                && let Some(is_dark) = sunrise_sunset.as_ref().and_then(|s| s.is_dark().ok())
+            {
+                if let Err(err) = theme_mode.set_is_dark(&helper, is_dark) {
+                    log::error!("Failed to update theme mode {err:?}");
+                }
+
+                if tk.apply_theme_global {
+                    let theme = match if theme_mode.is_dark {
+                        Theme::get_entry(&dark_helper)
+                    } else {
+                        Theme::get_entry(&light_helper)
+                    } {
+                        Ok(t) => t,
+                        Err((errs, t)) => {
+                            for err in errs {
+                                log::error!("{err}");
+                            }
+                            t
+                        }
+                    };
+                    if let Err(err) = theme.apply_exports() {
+                        log::error!("Failed to apply COSMIC theme exports. {err:?}");
+                    }
+
+                    set_gnome_desktop_interface(theme_mode.is_dark);
+                }
+            }
+        }
+
+        set_flatpak_app_overrides(&daemon_config.flatpak_theme_overrides, theme_mode.is_dark);
+
+        schedule_tx.send_if_modified(|state| {
+            let new_state = ThemeScheduleState {
+                auto_switch: theme_mode.auto_switch,
+                is_dark: theme_mode.is_dark,
+                override_until_next,
+                twilight: daemon_config.twilight,
+            };
+            let modified = *state != new_state;
+            *state = new_state;
+            modified
+        });
+
         let sunset_deadline =
             if let Some(Some(s)) = theme_mode.auto_switch.then_some(sunrise_sunset.as_mut()) {
                 Some(s.update_next()?)
@@ -248,6 +640,12 @@ pub async fn watch_theme(
                             log::error!("Error updating the theme mode {err:?}");
                         }
 
+                        if let Some(s) = sunrise_sunset.as_mut()
+                            && let Err(err) = s.refresh()
+                        {
+                            log::error!("Failed to recalculate sunrise and sunset {err:?}");
+                        }
+
                         override_until_next = sunrise_sunset.as_ref().is_some_and(|s| s.is_dark().is_ok_and(|s_is_dark| s_is_dark != theme_mode.is_dark));
 
                         if theme_mode.auto_switch && !auto_switch_prev {
@@ -335,6 +733,32 @@ pub async fn watch_theme(
                             log::error!("Failed to reset the cosmic theme exports. {err:?}");
                         }
                     },
+                    ThemeMsg::DaemonConfig(changes) => {
+                        let (errs, changes) = daemon_config.update_keys(&daemon_helper, &[changes]);
+
+                        for err in errs {
+                            log::error!("Error updating the settings daemon config {err:?}");
+                        }
+
+                        if changes.contains(&"automatic_location") {
+                            geoclue = daemon_config.automatic_location.then(crate::location::watch_geoclue);
+                            geoclue_position = None;
+                        }
+
+                        if changes.contains(&"twilight") && location.is_some() {
+                            pending_location = location.clone();
+                        }
+
+                        if changes.contains(&"manual_location")
+                            || changes.contains(&"location_city")
+                            || changes.contains(&"automatic_location")
+                        {
+                            let new_location = crate::location::resolve_location(&daemon_config, geodata, geoclue_position.as_ref(), timezone.as_deref());
+                            if new_location.is_some() && new_location != location {
+                                pending_location = new_location;
+                            }
+                        }
+                    }
                     ThemeMsg::Theme(is_dark) => {
                         let t = match Theme::get_entry(if is_dark {
                                 &dark_helper
@@ -379,8 +803,16 @@ pub async fn watch_theme(
                     override_until_next = false;
                     continue;
                 }
-                // update the theme mode
-                let Some(is_dark) = sunrise_sunset.as_ref().and_then(|s| s.is_dark().ok()) else {
+                // update the theme mode, for the new day if this is the midnight wake-up of
+                // polar day or night
+                let Some(s) = sunrise_sunset.as_mut() else {
+                    continue;
+                };
+                if let Err(err) = s.refresh() {
+                    log::error!("Failed to recalculate sunrise and sunset {err:?}");
+                    continue;
+                }
+                let Ok(is_dark) = s.is_dark() else {
                     continue;
                 };
 
@@ -409,66 +841,32 @@ pub async fn watch_theme(
                 }
             }
             location_update = location_updates.next() => {
-                if override_until_next {
+                let Some(new_timezone) = location_update else {
                     continue;
-                }
-                // set the next timer
-                // update the theme if necessary
-                let Some(location_result) = location_update else {
-                    continue;
-                };
-
-                let Ok(new_timezone) = location_result else {
-                    continue;
-                };
-
-                let Some(&GeoPosition { latitude, longitude }) = geodata.get(&new_timezone) else {
-                    log::error!("no matching geodata for {new_timezone}");
-                    continue;
-                };
-
-                coords = Some((latitude, longitude));
-                match SunriseSunset::new(latitude, longitude, None) {
-                    Ok(s) => {
-                        sunrise_sunset = Some(s);
-                    },
-                    Err(err) => {
-                        log::error!("Failed to calculate sunrise and sunset for current location {err:?}");
-                        sunrise_sunset = None;
-                        continue;
-                    },
                 };
 
-                if !theme_mode.auto_switch {
-                    continue;
+                _ = time_tx.send(TimeUpdate::Timezone(new_timezone.clone())).await;
+                timezone = Some(new_timezone);
+                let new_location = crate::location::resolve_location(&daemon_config, geodata, geoclue_position.as_ref(), timezone.as_deref());
+                if new_location.is_some() && new_location != location {
+                    pending_location = new_location;
                 }
-
-                let Some(is_dark) = sunrise_sunset.as_ref().unwrap().is_dark().ok() else {
-                    continue;
-                };
-
-                if let Err(err) = theme_mode.set_is_dark(&helper, is_dark) {
-                    log::error!("Failed to update theme mode {err:?}");
+            }
+            position = async {
+                match geoclue.as_mut() {
+                    Some((_, positions)) => positions.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                // GeoClue stopped or refused, so fall back to the timezone.
+                if position.is_none() {
+                    geoclue = None;
                 }
-                if tk.apply_theme_global {
-                    let theme = match if theme_mode.is_dark {
-                        Theme::get_entry(&dark_helper)
-                    } else {
-                        Theme::get_entry(&light_helper)
-                    } {
-                        Ok(t) => t,
-                        Err((errs, t)) => {
-                            for err in errs {
-                                log::error!("{err}");
-                            }
-                            t
-                        }
-                    };
-                    if let Err(err) = theme.apply_exports() {
-                        log::error!("Failed to apply COSMIC theme exports. {err:?}");
-                    }
 
-                    set_gnome_desktop_interface(theme_mode.is_dark);
+                geoclue_position = position;
+                let new_location = crate::location::resolve_location(&daemon_config, geodata, geoclue_position.as_ref(), timezone.as_deref());
+                if new_location.is_some() && new_location != location {
+                    pending_location = new_location;
                 }
             }
             time_change = time_changes.next() => {
@@ -479,18 +877,17 @@ pub async fn watch_theme(
                 // Suspend/resume and wall-clock steps (NTP, manual) do not advance tokio's
                 // monotonic `Instant` the same way. Recompute sunrise/sunset instants so the next
                 // sleep deadline and current day/night evaluation match wall-clock time.
-                let Some((latitude, longitude)) = coords else {
-                    continue;
-                };
+                if let Some(location) = &location {
+                    sunrise_sunset = sunrise_sunset_at(location, daemon_config.twilight);
+                }
 
-                match SunriseSunset::new(latitude, longitude, None) {
-                    Ok(s) => sunrise_sunset = Some(s),
-                    Err(err) => {
-                        log::error!("Failed to recalculate sunrise/sunset after {time_change:?}: {err:?}");
-                        sunrise_sunset = None;
-                        continue;
-                    }
-                };
+                // The time context follows the same sunrise and sunset before announcing the change.
+                _ = time_tx.send(TimeUpdate::Sun(sunrise_sunset.clone())).await;
+                _ = time_tx.send(TimeUpdate::Changed(time_change)).await;
+
+                if sunrise_sunset.is_none() {
+                    continue;
+                }
 
                 // If auto-switch isn't enabled, keep the timer state fresh and bail.
                 if !theme_mode.auto_switch {
@@ -641,3 +1038,451 @@ fn set_flatpak_overrides() {
         }
     });
 }
+
+/// Write per-app Flatpak overrides for apps which should not follow the system theme.
+///
+/// One task applies them in order, skipping to the latest when they change faster than
+/// `flatpak override` runs, so that only it reads and writes the record of applied overrides.
+fn set_flatpak_app_overrides(overrides: &[FlatpakThemeOverride], is_dark: bool) {
+    static REQUESTS: OnceLock<
+        tokio::sync::watch::Sender<Option<Vec<AppliedFlatpakThemeOverride>>>,
+    > = OnceLock::new();
+
+    let wanted: Vec<_> = overrides
+        .iter()
+        .map(|o| flatpak_app_settings(o, is_dark))
+        .collect();
+
+    let requests = REQUESTS.get_or_init(|| {
+        let (tx, mut rx) = tokio::sync::watch::channel(None);
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let Some(wanted) = rx.borrow_and_update().clone() else {
+                    continue;
+                };
+                apply_flatpak_app_overrides(wanted).await;
+            }
+        });
+        tx
+    });
+
+    requests.send_if_modified(|current| {
+        if current.as_ref() == Some(&wanted) {
+            return false;
+        }
+        *current = Some(wanted);
+        true
+    });
+}
+
+/// The settings an app's override writes in the current theme mode.
+fn flatpak_app_settings(o: &FlatpakThemeOverride, is_dark: bool) -> AppliedFlatpakThemeOverride {
+    let prefer_dark = o.variant.map_or(is_dark, |v| v == ThemeVariant::Dark);
+
+    let gtk_theme = match (&o.gtk_theme, o.variant) {
+        (Some(theme), _) if prefer_dark => Some(format!("{theme}:dark")),
+        (Some(theme), _) => Some(theme.clone()),
+        (None, Some(ThemeVariant::Dark)) => Some("adw-gtk3-dark".to_owned()),
+        (None, Some(ThemeVariant::Light)) => Some("adw-gtk3".to_owned()),
+        (None, None) => None,
+    };
+
+    AppliedFlatpakThemeOverride {
+        app_id: o.app_id.clone(),
+        gtk_theme,
+        icon_theme: o.icon_theme.clone(),
+        prefer_dark: o.variant.map(|v| v == ThemeVariant::Dark),
+    }
+}
+
+/// Writes the overrides of apps whose settings changed since they were last applied, and clears
+/// them from apps which were dropped from the list.
+async fn apply_flatpak_app_overrides(wanted: Vec<AppliedFlatpakThemeOverride>) {
+    let state_helper = CosmicSettingsDaemonState::config().ok();
+    let previous = state_helper
+        .as_ref()
+        .and_then(|helper| {
+            helper
+                .get::<Vec<AppliedFlatpakThemeOverride>>("flatpak_theme_overrides_applied")
+                .ok()
+        })
+        .unwrap_or_default();
+
+    let mut applied = Vec::with_capacity(wanted.len());
+
+    for old in previous
+        .iter()
+        .filter(|old| !wanted.iter().any(|o| o.app_id == old.app_id))
+    {
+        let cleared = AppliedFlatpakThemeOverride {
+            app_id: old.app_id.clone(),
+            ..Default::default()
+        };
+
+        // Keep the record of a failed removal so that it is tried again.
+        if !apply_flatpak_app_override(&cleared).await {
+            applied.push(old.clone());
+        }
+    }
+
+    for o in wanted {
+        let old = previous.iter().find(|old| old.app_id == o.app_id);
+        if old == Some(&o) || apply_flatpak_app_override(&o).await {
+            applied.push(o);
+        } else {
+            applied.push(old.cloned().unwrap_or_else(|| AppliedFlatpakThemeOverride {
+                app_id: o.app_id,
+                ..Default::default()
+            }));
+        }
+    }
+
+    if applied == previous {
+        return;
+    }
+
+    if let Some(helper) = state_helper
+        && let Err(err) = helper.set("flatpak_theme_overrides_applied", applied)
+    {
+        log::error!("Failed to record applied flatpak theme overrides. {err:?}");
+    }
+}
+
+/// Returns whether all of the app's settings were written.
+async fn apply_flatpak_app_override(o: &AppliedFlatpakThemeOverride) -> bool {
+    let gtk_theme = match &o.gtk_theme {
+        Some(theme) => format!("--env=GTK_THEME={theme}"),
+        None => "--unset-env=GTK_THEME".to_owned(),
+    };
+
+    let overridden = flatpak_app_override(&o.app_id, &[gtk_theme]).await;
+    let written =
+        set_flatpak_app_gtk_settings(&o.app_id, o.icon_theme.as_deref(), o.prefer_dark).await;
+
+    overridden && written
+}
+
+async fn flatpak_app_override(app_id: &str, args: &[String]) -> bool {
+    let status = tokio::process::Command::new("flatpak")
+        .arg("override")
+        .arg("--user")
+        .args(args)
+        .arg(app_id)
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            log::error!("Failed to set flatpak overrides for {app_id}: {status}");
+            false
+        }
+        Err(err) => {
+            log::error!("Failed to set flatpak overrides for {app_id}. {err:?}");
+            false
+        }
+    }
+}
+
+/// GTK has no environment variables for the icon theme or a preferred dark variant, so they are
+/// set in the settings.ini files that a Flatpak app reads from its private config directory.
+///
+/// GTK reads no other file there, so these are the app's own settings.ini files. Only the
+/// `gtk-icon-theme-name` and `gtk-application-prefer-dark-theme` keys are written, and they are
+/// removed again once the override is; any other settings in the files are kept.
+async fn set_flatpak_app_gtk_settings(
+    app_id: &str,
+    icon_theme: Option<&str>,
+    prefer_dark: Option<bool>,
+) -> bool {
+    let Some(config_dir) = std::env::home_dir().map(|home| home.join(".var/app").join(app_id))
+    else {
+        return true;
+    };
+
+    if !config_dir.exists() {
+        return true;
+    }
+
+    let mut written = true;
+
+    let prefer_dark = prefer_dark.map(|prefer_dark| prefer_dark.to_string());
+    for gtk in ["gtk-3.0", "gtk-4.0"] {
+        let path = config_dir.join("config").join(gtk).join("settings.ini");
+        let current = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+        let updated = settings_ini_with(&current, "gtk-icon-theme-name", icon_theme);
+        let updated = settings_ini_with(
+            &updated,
+            "gtk-application-prefer-dark-theme",
+            prefer_dark.as_deref(),
+        );
+        if updated == current {
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            _ = tokio::fs::create_dir_all(parent).await;
+        }
+
+        if let Err(err) = tokio::fs::write(&path, updated).await {
+            log::error!("Failed to write {}. {err:?}", path.display());
+            written = false;
+        }
+    }
+
+    written
+}
+
+/// Returns the contents of a GTK settings.ini with a key in its `[Settings]` group set, or
+/// removed if there is no value.
+fn settings_ini_with(current: &str, key: &str, value: Option<&str>) -> String {
+    let mut lines: Vec<String> = current
+        .lines()
+        .filter(|line| {
+            line.split_once('=')
+                .is_none_or(|(line_key, _)| line_key.trim() != key)
+        })
+        .map(String::from)
+        .collect();
+
+    if let Some(value) = value {
+        let entry = format!("{key}={value}");
+        match lines.iter().position(|line| line.trim() == "[Settings]") {
+            Some(pos) => lines.insert(pos + 1, entry),
+            None => {
+                lines.push("[Settings]".to_owned());
+                lines.push(entry);
+            }
+        }
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Daylight, SunriseSunset, ThemeScheduleState, daylight, flatpak_app_settings, schedule_days,
+        settings_ini_with, simulate,
+    };
+    use crate::location::{Location, LocationSource};
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+    use chrono_tz::{America, Europe};
+    use cosmic_settings_daemon_config::{FlatpakThemeOverride, ThemeVariant, Twilight};
+
+    #[test]
+    fn flatpak_app_variant() {
+        let mut o = FlatpakThemeOverride {
+            app_id: "org.example.App".to_owned(),
+            variant: None,
+            gtk_theme: Some("Adwaita".to_owned()),
+            icon_theme: None,
+        };
+
+        let settings = flatpak_app_settings(&o, true);
+        assert_eq!(settings.gtk_theme.as_deref(), Some("Adwaita:dark"));
+        assert_eq!(settings.prefer_dark, None);
+        assert_eq!(
+            flatpak_app_settings(&o, false).gtk_theme.as_deref(),
+            Some("Adwaita")
+        );
+
+        o.variant = Some(ThemeVariant::Light);
+        let settings = flatpak_app_settings(&o, true);
+        assert_eq!(settings.gtk_theme.as_deref(), Some("Adwaita"));
+        assert_eq!(settings.prefer_dark, Some(false));
+        assert_eq!(settings, flatpak_app_settings(&o, false));
+
+        o.gtk_theme = None;
+        o.variant = Some(ThemeVariant::Dark);
+        assert_eq!(
+            flatpak_app_settings(&o, false).gtk_theme.as_deref(),
+            Some("adw-gtk3-dark")
+        );
+    }
+
+    #[test]
+    fn settings_ini_keys() {
+        assert_eq!(
+            settings_ini_with("", "gtk-icon-theme-name", Some("Adwaita")),
+            "[Settings]\ngtk-icon-theme-name=Adwaita\n"
+        );
+
+        let ini = "[Settings]\ngtk-icon-theme-name=Pop\ngtk-font-name=Sans 10\n";
+        assert_eq!(
+            settings_ini_with(ini, "gtk-icon-theme-name", Some("Adwaita")),
+            "[Settings]\ngtk-icon-theme-name=Adwaita\ngtk-font-name=Sans 10\n"
+        );
+        assert_eq!(
+            settings_ini_with(ini, "gtk-application-prefer-dark-theme", Some("true")),
+            "[Settings]\ngtk-application-prefer-dark-theme=true\ngtk-icon-theme-name=Pop\ngtk-font-name=Sans 10\n"
+        );
+        assert_eq!(
+            settings_ini_with(ini, "gtk-icon-theme-name", None),
+            "[Settings]\ngtk-font-name=Sans 10\n"
+        );
+        assert_eq!(
+            settings_ini_with("[Settings]\n", "gtk-icon-theme-name", None),
+            "[Settings]\n"
+        );
+    }
+
+    #[test]
+    fn schedule_preview() {
+        let denver = Location {
+            latitude: 39.74,
+            longitude: -104.99,
+            source: LocationSource::Manual,
+        };
+
+        // Spans the start of daylight saving time in the United States, which must not shift
+        // the schedule by an hour.
+        let start = NaiveDate::from_ymd_opt(2025, 3, 8).unwrap();
+        let schedule = schedule_days(&denver, Twilight::Sunset, start, 3);
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule[1].0, "2025-03-09");
+
+        for (_, sunrise, sunset, kind) in &schedule {
+            assert!(sunrise < sunset);
+            assert_eq!(kind, "normal");
+        }
+        for days in schedule.windows(2) {
+            let delta = days[1].1 - days[0].1;
+            assert!((23 * 3600 + 55 * 60..24 * 3600).contains(&delta), "{delta}");
+        }
+
+        assert_eq!(
+            schedule_days(&denver, Twilight::Sunset, start, u32::MAX).len(),
+            366
+        );
+
+        let state = |auto_switch, override_until_next| ThemeScheduleState {
+            auto_switch,
+            is_dark: false,
+            override_until_next,
+            twilight: Twilight::Sunset,
+        };
+        assert_eq!(state(false, false).reason(), "manual");
+        assert_eq!(state(true, false).reason(), "scheduled");
+        assert_eq!(state(true, true).reason(), "overridden");
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        const TROMSO: (f64, f64) = (69.65, 18.96);
+        const MCMURDO: (f64, f64) = (-77.85, 166.67);
+
+        let date = |m, d| NaiveDate::from_ymd_opt(2025, m, d).unwrap();
+        let at =
+            |(lat, long): (f64, f64), date, twilight| daylight(lat, long, date, twilight).unwrap();
+
+        // Midnight sun in Tromsø is polar night in McMurdo, and the other way around.
+        assert_eq!(
+            at(TROMSO, date(6, 21), Twilight::Sunset),
+            Daylight::PolarDay
+        );
+        assert_eq!(
+            at(MCMURDO, date(6, 21), Twilight::Sunset),
+            Daylight::PolarNight
+        );
+        assert_eq!(
+            at(TROMSO, date(12, 21), Twilight::Sunset),
+            Daylight::PolarNight
+        );
+        assert_eq!(
+            at(MCMURDO, date(12, 21), Twilight::Sunset),
+            Daylight::PolarDay
+        );
+
+        // The sun does not rise above the horizon in Tromsø in late December, but it is high
+        // enough at noon for civil and nautical twilight.
+        assert!(matches!(
+            at(TROMSO, date(12, 21), Twilight::Civil),
+            Daylight::Normal { .. }
+        ));
+        assert!(matches!(
+            at(TROMSO, date(12, 21), Twilight::Nautical),
+            Daylight::Normal { .. }
+        ));
+
+        // Twilight moves the switch earlier in the morning and later in the evening.
+        let (
+            Daylight::Normal { sunrise, sunset },
+            Daylight::Normal {
+                sunrise: dawn,
+                sunset: dusk,
+            },
+        ) = (
+            at(TROMSO, date(3, 20), Twilight::Sunset),
+            at(TROMSO, date(3, 20), Twilight::Nautical),
+        )
+        else {
+            panic!("expected a sunrise and sunset in Tromsø at the equinox");
+        };
+        assert!(dawn < sunrise && sunset < dusk);
+
+        // Whether McMurdo is in polar day, polar night or neither, the schedule has a next
+        // wake-up rather than failing.
+        let tomorrow = Local::now().date_naive() + chrono::Days::new(1);
+        let noon = Local
+            .from_local_datetime(&tomorrow.and_hms_opt(12, 0, 0).unwrap())
+            .earliest()
+            .unwrap();
+        let mut mcmurdo =
+            SunriseSunset::new(MCMURDO.0, MCMURDO.1, Some(noon), Twilight::Sunset).unwrap();
+        assert!(mcmurdo.update_next().is_ok());
+
+        // Waking for a day other than the one calculated, as at midnight in polar day or night,
+        // needs a refresh before checking whether it is dark.
+        assert!(mcmurdo.is_dark().is_err());
+        mcmurdo.refresh().unwrap();
+        assert!(mcmurdo.is_dark().is_ok());
+
+        let tromso = Location {
+            latitude: TROMSO.0,
+            longitude: TROMSO.1,
+            source: LocationSource::Manual,
+        };
+        let kinds: Vec<_> = schedule_days(&tromso, Twilight::Sunset, date(5, 1), 365)
+            .into_iter()
+            .map(|(_, _, _, kind)| kind)
+            .collect();
+        assert!(kinds.contains(&String::from("polar-day")));
+        assert!(kinds.contains(&String::from("polar-night")));
+        assert!(kinds.contains(&String::from("normal")));
+    }
+
+    #[test]
+    fn simulate_in_timezone() {
+        // The sun stays up at midsummer in Tromsø, so the theme watcher next wakes at local
+        // midnight, which is 22:00 UTC in summer time.
+        let noon = Utc.with_ymd_and_hms(2025, 6, 21, 10, 0, 0).unwrap();
+        let (sunrise, sunset, is_dark, next) =
+            simulate(Europe::Oslo, 69.65, 18.96, Twilight::Sunset, noon).unwrap();
+        assert_eq!((sunrise, sunset, is_dark), (0, 0, false));
+        let midnight = Utc.with_ymd_and_hms(2025, 6, 21, 22, 0, 0).unwrap();
+        assert_eq!(next, midnight.timestamp());
+
+        // 15:00 and 22:00 on the day daylight saving time starts in Denver.
+        let afternoon = Utc.with_ymd_and_hms(2025, 3, 9, 21, 0, 0).unwrap();
+        let (sunrise, sunset, is_dark, next) =
+            simulate(America::Denver, 39.74, -104.99, Twilight::Sunset, afternoon).unwrap();
+        assert!(sunrise < afternoon.timestamp() && afternoon.timestamp() < sunset);
+        assert!(!is_dark);
+        assert_eq!(next, sunset);
+
+        // Late in the evening it is still the same local day, past its sunset, and the next
+        // switch is at the following sunrise.
+        let evening = Utc.with_ymd_and_hms(2025, 3, 10, 4, 0, 0).unwrap();
+        let (_, evening_sunset, is_dark, next) =
+            simulate(America::Denver, 39.74, -104.99, Twilight::Sunset, evening).unwrap();
+        assert_eq!(evening_sunset, sunset);
+        assert!(is_dark);
+        assert!((23 * 3600..25 * 3600).contains(&(next - sunrise)), "{next}");
+    }
+}
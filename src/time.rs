@@ -1,5 +1,11 @@
+use chrono::{DateTime, Local, Utc};
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 use zbus::fdo::PropertiesProxy;
+use zbus::object_server::SignalEmitter;
+
+use crate::location::{GeoPosition, Geodata, Location, LocationSource};
+use crate::theme::{Daylight, SunriseSunset};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeChange {
@@ -9,6 +15,29 @@ pub enum TimeChange {
     Resume,
 }
 
+impl TimeChange {
+    /// The reason given by the time context's `TimeChanged` signal.
+    fn reason(self) -> &'static str {
+        match self {
+            Self::WallClockChange => "wall-clock",
+            Self::Resume => "resume",
+        }
+    }
+}
+
+/// Sent by the theme watcher to the time context, so that one set of timezone and clock
+/// watchers and one sunrise and sunset calculation serve both.
+#[derive(Debug)]
+pub enum TimeUpdate {
+    /// The system timezone.
+    Timezone(String),
+    /// Sunrise and sunset at the location in use, or `None` if no location is known.
+    Sun(Option<SunriseSunset>),
+    /// The system resumed or the wall clock jumped. Sent once the sunrise and sunset have been
+    /// recalculated.
+    Changed(TimeChange),
+}
+
 #[zbus::proxy(
     default_service = "org.freedesktop.login1",
     interface = "org.freedesktop.login1.Manager",
@@ -19,6 +48,25 @@ trait LogindManager {
     fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
 }
 
+#[zbus::proxy(
+    default_service = "org.freedesktop.timedate1",
+    interface = "org.freedesktop.timedate1",
+    default_path = "/org/freedesktop/timedate1"
+)]
+pub trait Timedate1 {
+    #[zbus(property)]
+    fn timezone(&self) -> zbus::Result<String>;
+
+    #[zbus(property, name = "NTP")]
+    fn ntp(&self) -> zbus::Result<bool>;
+
+    #[zbus(property, name = "CanNTP")]
+    fn can_ntp(&self) -> zbus::Result<bool>;
+
+    #[zbus(property, name = "LocalRTC")]
+    fn local_rtc(&self) -> zbus::Result<bool>;
+}
+
 pub struct TimeWatcher {
     _conn: zbus::Connection,
     tasks: Vec<tokio::task::JoinHandle<()>>,
@@ -131,3 +179,450 @@ pub async fn watch_time_changes() -> anyhow::Result<(
         tokio_stream::wrappers::ReceiverStream::new(rx),
     ))
 }
+
+pub static TIME_CONTEXT_PATH: &str = "/com/system76/CosmicSettingsDaemon/TimeContext";
+
+/// Timezone, location and daylight, so that clocks and applets share one view of the time.
+#[derive(Debug, Default)]
+pub struct TimeContext {
+    timezone: String,
+    location: Option<Location>,
+    /// Timezone of the detected location, when it differs from the system timezone.
+    suggested_timezone: String,
+    /// Whether the city database is installed, without which no timezone can be suggested.
+    has_cities: bool,
+    /// The timedate1 NTP, CanNTP and LocalRTC properties, as last reported.
+    ntp: bool,
+    can_ntp: bool,
+    local_rtc: bool,
+    /// Sunrise and sunset from the theme watcher, moved on to the current day.
+    sun: Option<SunriseSunset>,
+}
+
+#[derive(Debug, PartialEq)]
+struct TimeContextSnapshot {
+    timezone: String,
+    location: (f64, f64, String),
+    suggested_timezone: String,
+    sunrise: i64,
+    sunset: i64,
+    is_dark: bool,
+    next_transition: i64,
+}
+
+impl TimeContext {
+    /// Suggest the timezone of a location which was not itself derived from the timezone.
+    fn suggest_timezone(&mut self, geodata: &Geodata) {
+        self.suggested_timezone = self
+            .location
+            .as_ref()
+            .filter(|location| location.source != LocationSource::Timezone)
+            .and_then(|location| {
+                let position = GeoPosition {
+                    latitude: location.latitude,
+                    longitude: location.longitude,
+                };
+                crate::location::nearest_timezone(&geodata.cities, &position)
+            })
+            .filter(|timezone| *timezone != self.timezone)
+            .unwrap_or_default();
+    }
+
+    fn refresh(&mut self, now: DateTime<Utc>) {
+        if let Some(sun) = self.sun.as_mut()
+            && let Err(err) = sun.refresh_at(now)
+        {
+            log::error!("Failed to calculate sunrise and sunset: {err:?}");
+            self.sun = None;
+        }
+    }
+
+    fn is_dark_at(&self, now: DateTime<Utc>) -> bool {
+        self.sun.as_ref().is_some_and(|sun| sun.is_dark_at(now))
+    }
+
+    fn next_transition_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.sun.as_ref()?.next_transition_at(now)
+    }
+
+    fn snapshot(&self, now: DateTime<Utc>) -> TimeContextSnapshot {
+        let (sunrise, sunset) = match self.sun.as_ref().map(SunriseSunset::daylight) {
+            Some(Daylight::Normal { sunrise, sunset }) => (sunrise.timestamp(), sunset.timestamp()),
+            _ => (0, 0),
+        };
+
+        TimeContextSnapshot {
+            timezone: self.timezone.clone(),
+            location: location_tuple(self.location.as_ref()),
+            suggested_timezone: self.suggested_timezone.clone(),
+            sunrise,
+            sunset,
+            is_dark: self.is_dark_at(now),
+            next_transition: self.next_transition_at(now).map_or(0, |t| t.timestamp()),
+        }
+    }
+}
+
+fn location_tuple(location: Option<&Location>) -> (f64, f64, String) {
+    match location {
+        Some(location) => (
+            location.latitude,
+            location.longitude,
+            location.source.as_str().to_owned(),
+        ),
+        None => (0.0, 0.0, String::new()),
+    }
+}
+
+#[zbus::interface(name = "com.system76.CosmicSettingsDaemon.TimeContext")]
+impl TimeContext {
+    /// IANA name of the system timezone, such as `Europe/Oslo`; or empty if unknown.
+    #[zbus(property)]
+    async fn timezone(&self) -> String {
+        self.timezone.clone()
+    }
+
+    /// Latitude and longitude in use, and the source they came from.
+    ///
+    /// The source is one of `manual`, `city`, `geoclue` or `timezone`; or empty if no location
+    /// is known.
+    #[zbus(property)]
+    async fn location(&self) -> (f64, f64, String) {
+        location_tuple(self.location.as_ref())
+    }
+
+    /// Today's sunrise in seconds since the Unix epoch, or 0 if no location is known or the sun
+    /// does not rise or set today.
+    #[zbus(property)]
+    async fn sunrise(&self) -> i64 {
+        self.snapshot(Utc::now()).sunrise
+    }
+
+    /// Today's sunset in seconds since the Unix epoch, or 0 if no location is known or the sun
+    /// does not rise or set today.
+    #[zbus(property)]
+    async fn sunset(&self) -> i64 {
+        self.snapshot(Utc::now()).sunset
+    }
+
+    /// Whether the sun is down at the current location.
+    #[zbus(property)]
+    async fn is_dark(&self) -> bool {
+        self.is_dark_at(Utc::now())
+    }
+
+    /// The next sunrise or sunset in seconds since the Unix epoch, or 0 if unknown.
+    #[zbus(property)]
+    async fn next_transition(&self) -> i64 {
+        self.snapshot(Utc::now()).next_transition
+    }
+
+    /// Set the system timezone, such as `Europe/Oslo`, through the system helper.
+    async fn set_timezone(&self, timezone: &str) -> zbus::fdo::Result<()> {
+        if timezone.is_empty() {
+            return Err(zbus::fdo::Error::InvalidArgs("empty timezone".into()));
+        }
+
+        crate::system_helper().await?.set_timezone(timezone).await?;
+        Ok(())
+    }
+
+    /// Timezone of the detected location, offered to the user when it differs from the system
+    /// timezone; or empty if there is nothing to suggest.
+    ///
+    /// Fails with `NotSupported` if the city database is not installed.
+    #[zbus(property)]
+    async fn suggested_timezone(&self) -> zbus::fdo::Result<String> {
+        if !self.has_cities {
+            return Err(zbus::fdo::Error::NotSupported(
+                "no geonames city database is installed".into(),
+            ));
+        }
+
+        Ok(self.suggested_timezone.clone())
+    }
+
+    /// Whether the clock is synchronized over the network.
+    #[zbus(property, name = "NTP")]
+    async fn ntp(&self) -> bool {
+        self.ntp
+    }
+
+    #[zbus(property, name = "NTP")]
+    async fn set_ntp(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        crate::system_helper().await?.set_ntp(enabled).await?;
+        Ok(())
+    }
+
+    /// Whether network time synchronization is available.
+    #[zbus(property, name = "CanNTP")]
+    async fn can_ntp(&self) -> bool {
+        self.can_ntp
+    }
+
+    /// Whether the hardware clock keeps local time instead of UTC.
+    #[zbus(property, name = "LocalRTC")]
+    async fn local_rtc(&self) -> bool {
+        self.local_rtc
+    }
+
+    #[zbus(property, name = "LocalRTC")]
+    async fn set_local_rtc(&self, local: bool) -> zbus::fdo::Result<()> {
+        crate::system_helper().await?.set_local_rtc(local).await?;
+        Ok(())
+    }
+
+    /// Emitted once the properties are updated after the system resumes (`resume`), the wall
+    /// clock jumps (`wall-clock`) or the timezone changes (`timezone`).
+    #[zbus(signal)]
+    async fn time_changed(emitter: &SignalEmitter<'_>, reason: &str) -> zbus::Result<()>;
+}
+
+/// Keep the time context served at [`TIME_CONTEXT_PATH`] up to date with the location and the
+/// updates from the theme watcher.
+pub async fn time_context_task(
+    mut location_rx: tokio::sync::watch::Receiver<Option<Location>>,
+    mut updates: tokio::sync::mpsc::Receiver<TimeUpdate>,
+    connection: zbus::Connection,
+) {
+    let Ok(interface) = connection
+        .object_server()
+        .interface::<_, TimeContext>(TIME_CONTEXT_PATH)
+        .await
+    else {
+        return;
+    };
+
+    let geodata = crate::location::load_geodata().await;
+    interface.get_mut().await.has_cities = !geodata.cities.cities().is_empty();
+
+    let timedate = async {
+        let conn = zbus::Connection::system().await?;
+        Timedate1Proxy::new(&conn).await
+    }
+    .await
+    .inspect_err(|err| log::warn!("Failed to connect to timedate1: {err}"))
+    .ok();
+
+    // Follow the timedate1 properties through their change signals rather than asking for them
+    // on every read.
+    let (mut ntp_changes, mut can_ntp_changes, mut local_rtc_changes) = match &timedate {
+        Some(timedate) => {
+            let changes = (
+                Some(timedate.receive_ntp_changed().await),
+                Some(timedate.receive_can_ntp_changed().await),
+                Some(timedate.receive_local_rtc_changed().await),
+            );
+
+            let mut context = interface.get_mut().await;
+            context.ntp = timedate.ntp().await.unwrap_or(false);
+            context.can_ntp = timedate.can_ntp().await.unwrap_or(false);
+            context.local_rtc = timedate.local_rtc().await.unwrap_or(false);
+            changes
+        }
+        None => (None, None, None),
+    };
+
+    let mut updates_open = true;
+    let mut location_rx_open = true;
+
+    loop {
+        update_time_context(&interface, |context| {
+            context.location = location_rx.borrow_and_update().clone();
+            context.suggest_timezone(geodata);
+        })
+        .await;
+
+        // Wake for the next sunrise or sunset, or at midnight to move on to the next day.
+        let now = Utc::now();
+        let midnight = Local::now()
+            .date_naive()
+            .succ_opt()
+            .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+            .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+            .map(|midnight| midnight.with_timezone(&Utc));
+        let deadline = interface
+            .get()
+            .await
+            .next_transition_at(now)
+            .into_iter()
+            .chain(midnight)
+            .min()
+            .and_then(|deadline| (deadline - now).to_std().ok())
+            .map(|delay| Instant::now() + delay);
+
+        let reason = tokio::select! {
+            update = updates.recv(), if updates_open => match update {
+                Some(TimeUpdate::Timezone(timezone)) => {
+                    update_time_context(&interface, |context| {
+                        context.timezone = timezone;
+                        context.suggest_timezone(geodata);
+                    })
+                    .await;
+                    Some("timezone")
+                }
+                Some(TimeUpdate::Sun(sun)) => {
+                    update_time_context(&interface, |context| context.sun = sun).await;
+                    None
+                }
+                Some(TimeUpdate::Changed(change)) => Some(change.reason()),
+                None => {
+                    updates_open = false;
+                    None
+                }
+            },
+
+            ntp = next_value(&mut ntp_changes) => {
+                interface.get_mut().await.ntp = ntp;
+                _ = interface.get().await.ntp_changed(interface.signal_emitter()).await;
+                None
+            }
+
+            can_ntp = next_value(&mut can_ntp_changes) => {
+                interface.get_mut().await.can_ntp = can_ntp;
+                _ = interface.get().await.can_ntp_changed(interface.signal_emitter()).await;
+                None
+            }
+
+            local_rtc = next_value(&mut local_rtc_changes) => {
+                interface.get_mut().await.local_rtc = local_rtc;
+                _ = interface.get().await.local_rtc_changed(interface.signal_emitter()).await;
+                None
+            }
+
+            changed = location_rx.changed(), if location_rx_open => {
+                location_rx_open = changed.is_ok();
+                None
+            }
+
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => None,
+        };
+
+        if let Some(reason) = reason {
+            // Move on to the current day before announcing the change.
+            update_time_context(&interface, |_| ()).await;
+            _ = TimeContext::time_changed(interface.signal_emitter(), reason).await;
+        }
+    }
+}
+
+/// The next value of a timedate1 property, or never once its changes stop.
+async fn next_value(changes: &mut Option<zbus::proxy::PropertyStream<'static, bool>>) -> bool {
+    while let Some(stream) = changes.as_mut() {
+        match stream.next().await {
+            Some(change) => match change.get().await {
+                Ok(value) => return value,
+                Err(err) => log::warn!("Failed to read timedate1 {}: {err}", change.name()),
+            },
+            None => *changes = None,
+        }
+    }
+
+    std::future::pending().await
+}
+
+/// Change the time context, move its sunrise and sunset on to the current day, and signal the
+/// properties which changed.
+async fn update_time_context(
+    interface: &zbus::object_server::InterfaceRef<TimeContext>,
+    update: impl FnOnce(&mut TimeContext),
+) {
+    let now = Utc::now();
+    let previous = interface.get().await.snapshot(now);
+    {
+        let mut context = interface.get_mut().await;
+        update(&mut context);
+        context.refresh(now);
+    }
+    let current = interface.get().await.snapshot(now);
+    emit_time_context_changes(interface, &previous, &current).await;
+}
+
+async fn emit_time_context_changes(
+    interface: &zbus::object_server::InterfaceRef<TimeContext>,
+    previous: &TimeContextSnapshot,
+    current: &TimeContextSnapshot,
+) {
+    let emitter = interface.signal_emitter();
+    let context = interface.get().await;
+
+    if previous.timezone != current.timezone {
+        _ = context.timezone_changed(emitter).await;
+    }
+    if previous.location != current.location {
+        _ = context.location_changed(emitter).await;
+    }
+    if previous.suggested_timezone != current.suggested_timezone {
+        _ = context.suggested_timezone_changed(emitter).await;
+    }
+    if previous.sunrise != current.sunrise {
+        _ = context.sunrise_changed(emitter).await;
+    }
+    if previous.sunset != current.sunset {
+        _ = context.sunset_changed(emitter).await;
+    }
+    if previous.is_dark != current.is_dark {
+        _ = context.is_dark_changed(emitter).await;
+    }
+    if previous.next_transition != current.next_transition {
+        _ = context.next_transition_changed(emitter).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeContext;
+    use crate::location::{Location, LocationSource};
+    use crate::theme::{Daylight, SunriseSunset};
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+    use cosmic_settings_daemon_config::Twilight;
+
+    #[test]
+    fn time_context_transitions() {
+        let context = TimeContext {
+            location: Some(Location {
+                latitude: 39.74,
+                longitude: -104.99,
+                source: LocationSource::Manual,
+            }),
+            sun: SunriseSunset::on(
+                Local,
+                39.74,
+                -104.99,
+                NaiveDate::from_ymd_opt(2025, 6, 21).unwrap(),
+                Twilight::Sunset,
+            )
+            .ok(),
+            ..TimeContext::default()
+        };
+
+        let Some(Daylight::Normal { sunrise, sunset }) =
+            context.sun.as_ref().map(SunriseSunset::daylight)
+        else {
+            panic!("expected a sunrise and sunset in Denver");
+        };
+        assert!(sunrise < sunset);
+
+        // Denver is dark at 08:00 UTC and light at 18:00 UTC around the solstice.
+        let night = Utc.with_ymd_and_hms(2025, 6, 21, 8, 0, 0).unwrap();
+        let day = Utc.with_ymd_and_hms(2025, 6, 21, 18, 0, 0).unwrap();
+        assert!(context.is_dark_at(night));
+        assert!(!context.is_dark_at(day));
+        assert_eq!(context.next_transition_at(night), Some(sunrise));
+        assert_eq!(context.next_transition_at(day), Some(sunset));
+
+        let after_sunset = sunset + chrono::Duration::hours(1);
+        let next_sunrise = context.next_transition_at(after_sunset).unwrap();
+        assert!(next_sunrise > after_sunset);
+        assert!(next_sunrise - sunrise < chrono::Duration::hours(25));
+
+        let snapshot = context.snapshot(day);
+        assert_eq!(snapshot.location.2, "manual");
+        assert_eq!(snapshot.sunset, sunset.timestamp());
+    }
+}
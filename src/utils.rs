@@ -15,3 +15,25 @@ pub async fn zbus_system_connection() -> Option<zbus::Connection> {
     _ = CONNECTION.set(conn.clone());
     Some(conn)
 }
+
+/// Connects a client to a server over a private socket, for tests against mock services.
+/// `serve` adds the server's objects.
+#[cfg(test)]
+pub async fn p2p_pair(
+    serve: impl FnOnce(
+        zbus::connection::Builder<'static>,
+    ) -> zbus::Result<zbus::connection::Builder<'static>>,
+) -> (zbus::Connection, zbus::Connection) {
+    let guid = zbus::Guid::generate();
+    let (client, server) = tokio::net::UnixStream::pair().unwrap();
+    let server = zbus::connection::Builder::unix_stream(server)
+        .server(guid)
+        .unwrap()
+        .p2p();
+
+    futures::try_join!(
+        zbus::connection::Builder::unix_stream(client).p2p().build(),
+        serve(server).unwrap().build(),
+    )
+    .unwrap()
+}
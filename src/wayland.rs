@@ -2,36 +2,186 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use calloop_wayland_source::WaylandSource;
+use cctk::cosmic_protocols::a11y::v1::client::cosmic_a11y_manager_v1::{
+    self, ActiveState, CosmicA11yManagerV1, Filter,
+};
 use cctk::cosmic_protocols::keyboard_layout::v1::client::zcosmic_keyboard_layout_v1::ZcosmicKeyboardLayoutV1;
+use cctk::cosmic_protocols::toplevel_info::v1::client::zcosmic_toplevel_handle_v1;
 use cctk::keyboard_layout::{KeyboardLayoutHandler, KeyboardLayoutState};
 use cctk::sctk::registry::{ProvidesRegistryState, RegistryState};
 use cctk::sctk::seat::{Capability, SeatHandler, SeatState};
 use cctk::sctk::{self};
+use cctk::toplevel_info::{ToplevelInfoHandler, ToplevelInfoState};
+use cctk::wayland_client::backend::ObjectId;
 use cctk::wayland_client::globals::registry_queue_init;
 use cctk::wayland_client::protocol::{wl_keyboard, wl_seat};
-use cctk::wayland_client::{Connection, QueueHandle, delegate_noop};
+use cctk::wayland_client::{Connection, Dispatch, Proxy, QueueHandle, WEnum, delegate_noop};
+use cctk::wayland_protocols::ext::foreign_toplevel_list::v1::client::ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1;
 use cosmic_comp_config::XkbConfig;
 use cosmic_config::ConfigGet;
+use cosmic_settings_daemon_config::{CosmicSettingsDaemonConfig, InputSourceMemory};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 pub enum Cmd {
     InputSourceSwitch,
+    /// Switch to the previous input source.
+    PreviousInputSource,
+    /// Switch to the input source at the given index of the xkb config's layouts.
+    SetInputSource(u32),
+    /// Change what the active input source is remembered for.
+    SetInputSourceMemory(InputSourceMemory),
 }
 
-pub fn run() -> calloop::channel::Sender<Cmd> {
-    let conn = Connection::connect_to_env().unwrap();
-    let (sender, channel) = calloop::channel::channel();
-    thread::spawn(move || thread(conn, channel));
+/// The compositor's magnifier and inverted colors, as it last reported them, and changes
+/// applied when it connects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct A11yState {
+    pub magnifier: bool,
+    pub invert_colors: bool,
+    pub pending_magnifier: Option<bool>,
+    pub pending_invert_colors: Option<bool>,
+}
+
+/// Sends commands to the Wayland thread while it is connected to the compositor.
+#[derive(Clone, Default)]
+pub struct Sender(Arc<Mutex<Option<calloop::channel::Sender<Cmd>>>>);
+
+impl Sender {
+    /// Send a command, which is dropped if the compositor is unavailable.
+    pub fn send(&self, cmd: Cmd) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|sender| sender.send(cmd).is_ok())
+    }
+}
+
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Start the Wayland thread, which publishes the index of the active input source to `active`,
+/// the compositor's accessibility state to `a11y`, and whether it is connected to the
+/// compositor to `available`.
+///
+/// The thread reconnects whenever the connection is lost, such as when the compositor restarts.
+pub fn run(
+    active: tokio::sync::watch::Sender<u32>,
+    a11y: tokio::sync::watch::Sender<A11yState>,
+    available: tokio::sync::watch::Sender<bool>,
+) -> Sender {
+    let sender = Sender::default();
+    let thread_sender = sender.clone();
+    thread::spawn(move || thread(thread_sender, active, a11y, available));
     sender
 }
 
+/// A keyboard layout from the xkb config.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputSource {
+    pub layout: String,
+    pub variant: String,
+    pub description: String,
+}
+
+/// Settings daemon config key for [`InputSourceMemory`].
+pub const INPUT_SOURCE_MEMORY_KEY: &str = "input_source_memory";
+
+pub fn input_source_memory() -> InputSourceMemory {
+    CosmicSettingsDaemonConfig::config()
+        .and_then(|config| config.get(INPUT_SOURCE_MEMORY_KEY))
+        .unwrap_or_default()
+}
+
+const XKB_RULES_LIST: &str = "/usr/share/X11/xkb/rules/base.lst";
+
+/// The xkb rules list, read once as it only changes with the xkeyboard-config package.
+fn xkb_rules() -> &'static str {
+    static RULES: OnceLock<String> = OnceLock::new();
+    RULES.get_or_init(|| std::fs::read_to_string(XKB_RULES_LIST).unwrap_or_default())
+}
+
+/// [`input_sources`] read off the async runtime, as it reads the xkb config from disk.
+pub async fn load_input_sources() -> Vec<InputSource> {
+    tokio::task::spawn_blocking(input_sources)
+        .await
+        .unwrap_or_default()
+}
+
+/// The layouts of the xkb config, in the order of their group indices.
+fn input_sources() -> Vec<InputSource> {
+    let Some(xkb) = xkb_config() else {
+        return Vec::new();
+    };
+
+    let rules = xkb_rules();
+    let mut variants = xkb.variant.split(',');
+
+    xkb.layout
+        .split_terminator(',')
+        .map(|layout| {
+            let layout = layout.trim().to_owned();
+            let variant = variants.next().unwrap_or("").trim().to_owned();
+            let description =
+                describe_layout(rules, &layout, &variant).unwrap_or_else(|| layout.clone());
+            InputSource {
+                layout,
+                variant,
+                description,
+            }
+        })
+        .collect()
+}
+
+/// Find the human-readable name of a layout and variant in an xkb rules list.
+fn describe_layout(rules: &str, layout: &str, variant: &str) -> Option<String> {
+    let mut section = "";
+
+    for line in rules.lines() {
+        if let Some(name) = line.strip_prefix('!') {
+            section = name.trim();
+            continue;
+        }
+
+        let Some((name, description)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let description = description.trim();
+
+        match section {
+            "layout" if variant.is_empty() && name == layout => {
+                return Some(description.to_owned());
+            }
+            "variant" if !variant.is_empty() && name == variant => {
+                if let Some(description) = description
+                    .strip_prefix(layout)
+                    .and_then(|rest| rest.strip_prefix(": "))
+                {
+                    return Some(description.to_owned());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
 const COSMIC_COMP_CONFIG: &str = "com.system76.CosmicComp";
 const COSMIC_COMP_CONFIG_VERSION: u64 = 1;
 const XKB_CONFIG_KEY: &str = "xkb_config";
 
 fn xkb_config() -> Option<XkbConfig> {
-    let config =
-        cosmic_config::Config::new(COSMIC_COMP_CONFIG, COSMIC_COMP_CONFIG_VERSION).unwrap();
+    let config = match cosmic_config::Config::new(COSMIC_COMP_CONFIG, COSMIC_COMP_CONFIG_VERSION) {
+        Ok(config) => config,
+        Err(why) => {
+            log::error!("failed to open config '{}': {}", COSMIC_COMP_CONFIG, why);
+            return None;
+        }
+    };
 
     match config.get(XKB_CONFIG_KEY) {
         Ok(xkb) => Some(xkb),
@@ -61,21 +211,152 @@ struct AppData {
     seat_state: SeatState,
     registry_state: RegistryState,
     keyboard_layout_state: KeyboardLayoutState,
+    toplevel_info_state: ToplevelInfoState,
     running: bool,
     keyboard: Option<Keyboard>,
     current_layout: u32,
+    active: tokio::sync::watch::Sender<u32>,
+    memory: InputSourceMemory,
+    /// Window or app ID that the active input source is remembered for.
+    focused: Option<String>,
+    remembered: HashMap<String, u32>,
+    a11y_manager: Option<CosmicA11yManagerV1>,
+    a11y: tokio::sync::watch::Sender<A11yState>,
+    /// The color filter last reported, kept when inverting colors.
+    filter: Filter,
 }
 
 impl AppData {
     fn input_source_switch(&mut self) {
-        if let Some(keyboard) = &self.keyboard
-            && let Some(xkb) = xkb_config()
-        {
-            let count = xkb.layout.split_terminator(',').count();
+        self.cycle_input_source(1);
+    }
 
-            let group = (self.current_layout + 1) % count as u32;
+    fn previous_input_source(&mut self) {
+        self.cycle_input_source(-1);
+    }
+
+    fn cycle_input_source(&mut self, step: i64) {
+        if let Some(xkb) = xkb_config() {
+            let count = xkb.layout.split_terminator(',').count() as i64;
+            if count == 0 {
+                return;
+            }
+
+            let group = (i64::from(self.current_layout) + step).rem_euclid(count);
+            self.set_input_source(group as u32);
+        }
+    }
+
+    fn set_input_source(&mut self, group: u32) {
+        if let Some(keyboard) = &self.keyboard {
             keyboard.keyboard_layout.set_group(group);
-            self.current_layout = group;
+            self.layout_changed(group);
+        }
+    }
+
+    fn layout_changed(&mut self, group: u32) {
+        self.current_layout = group;
+        self.active.send_if_modified(|active| {
+            let modified = *active != group;
+            *active = group;
+            modified
+        });
+
+        if let Some(focused) = &self.focused {
+            self.remembered.insert(focused.clone(), group);
+        }
+    }
+
+    fn set_input_source_memory(&mut self, memory: InputSourceMemory) {
+        if self.memory != memory {
+            self.memory = memory;
+            self.remembered.clear();
+            self.focus_changed();
+        }
+    }
+
+    /// Restore the input source remembered for the newly activated window or app.
+    fn focus_changed(&mut self) {
+        let focused = match self.memory {
+            InputSourceMemory::Global => None,
+            InputSourceMemory::Window => {
+                self.activated_toplevel(|toplevel, _| toplevel.id().protocol_id().to_string())
+            }
+            InputSourceMemory::Application => {
+                self.activated_toplevel(|_, app_id| app_id.to_owned())
+            }
+        };
+
+        if focused == self.focused {
+            return;
+        }
+
+        self.focused.clone_from(&focused);
+        let Some(focused) = focused else {
+            return;
+        };
+
+        match self.remembered.get(&focused).copied() {
+            Some(group) if group != self.current_layout => self.set_input_source(group),
+            Some(_) => (),
+            None => {
+                self.remembered.insert(focused, self.current_layout);
+            }
+        }
+    }
+
+    fn activated_toplevel(
+        &self,
+        key: impl Fn(&ExtForeignToplevelHandleV1, &str) -> String,
+    ) -> Option<String> {
+        self.toplevel_info_state
+            .toplevels()
+            .find(|info| {
+                info.state
+                    .contains(&zcosmic_toplevel_handle_v1::State::Activated)
+            })
+            .map(|info| key(&info.foreign_toplevel, &info.app_id))
+    }
+
+    /// Ask the compositor to apply the pending accessibility changes, if it is connected.
+    fn apply_a11y(&mut self) {
+        let Some(manager) = &self.a11y_manager else {
+            return;
+        };
+
+        let mut pending = (None, None);
+        self.a11y.send_if_modified(|state| {
+            pending = (
+                state.pending_magnifier.take(),
+                state.pending_invert_colors.take(),
+            );
+            pending != (None, None)
+        });
+
+        let active_state = |enabled| {
+            if enabled {
+                ActiveState::Enabled
+            } else {
+                ActiveState::Disabled
+            }
+        };
+
+        if let Some(magnifier) = pending.0 {
+            manager.set_magnifier(active_state(magnifier));
+        }
+
+        if let Some(invert_colors) = pending.1 {
+            if manager.version() >= 2 {
+                manager.set_screen_filter(active_state(invert_colors), self.filter);
+            } else {
+                log::warn!("the compositor does not support inverting colors");
+            }
+        }
+    }
+
+    fn forget_toplevel(&mut self, id: ObjectId) {
+        if self.memory == InputSourceMemory::Window {
+            self.remembered.remove(&id.protocol_id().to_string());
         }
     }
 }
@@ -89,7 +370,76 @@ impl KeyboardLayoutHandler for AppData {
         _keyboard_layout: &ZcosmicKeyboardLayoutV1,
         group: u32,
     ) {
-        self.current_layout = group;
+        self.layout_changed(group);
+    }
+}
+
+impl ToplevelInfoHandler for AppData {
+    fn toplevel_info_state(&mut self) -> &mut ToplevelInfoState {
+        &mut self.toplevel_info_state
+    }
+
+    fn new_toplevel(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _toplevel: &ExtForeignToplevelHandleV1,
+    ) {
+        self.focus_changed();
+    }
+
+    fn update_toplevel(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _toplevel: &ExtForeignToplevelHandleV1,
+    ) {
+        self.focus_changed();
+    }
+
+    fn toplevel_closed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        toplevel: &ExtForeignToplevelHandleV1,
+    ) {
+        self.forget_toplevel(toplevel.id());
+        self.focus_changed();
+    }
+}
+
+impl Dispatch<CosmicA11yManagerV1, ()> for AppData {
+    fn event(
+        state: &mut Self,
+        _manager: &CosmicA11yManagerV1,
+        event: cosmic_a11y_manager_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            cosmic_a11y_manager_v1::Event::Magnifier { active } => {
+                let magnifier = active == WEnum::Value(ActiveState::Enabled);
+                state.a11y.send_if_modified(|a11y| {
+                    let modified = a11y.magnifier != magnifier;
+                    a11y.magnifier = magnifier;
+                    modified
+                });
+            }
+            cosmic_a11y_manager_v1::Event::ScreenFilter { inverted, filter } => {
+                if let WEnum::Value(filter) = filter {
+                    state.filter = filter;
+                }
+
+                let invert_colors = inverted == WEnum::Value(ActiveState::Enabled);
+                state.a11y.send_if_modified(|a11y| {
+                    let modified = a11y.invert_colors != invert_colors;
+                    a11y.invert_colors = invert_colors;
+                    modified
+                });
+            }
+            _ => (),
+        }
     }
 }
 
@@ -148,43 +498,148 @@ impl SeatHandler for AppData {
     }
 }
 
-fn thread(conn: Connection, channel: calloop::channel::Channel<Cmd>) {
-    let (globals, event_queue) = registry_queue_init(&conn).unwrap();
+fn thread(
+    sender: Sender,
+    active: tokio::sync::watch::Sender<u32>,
+    a11y: tokio::sync::watch::Sender<A11yState>,
+    available: tokio::sync::watch::Sender<bool>,
+) {
+    let mut retry = RETRY_MIN;
+    let mut warned = false;
+
+    loop {
+        let started = Instant::now();
+        let result = session(&sender, &active, &a11y, &available);
+
+        sender.0.lock().unwrap().take();
+        available.send_replace(false);
+
+        // A connection that lasted a while was not a failure to connect.
+        if started.elapsed() > RETRY_MAX {
+            retry = RETRY_MIN;
+            warned = false;
+        }
+
+        match result {
+            Ok(()) => return,
+            // Outside a Wayland session every attempt fails, so only the first is a warning.
+            Err(why) if warned => {
+                log::debug!("wayland connection unavailable, retrying in {retry:?}: {why:?}");
+            }
+            Err(why) => {
+                log::warn!("wayland connection unavailable, retrying in {retry:?}: {why:?}");
+                warned = true;
+            }
+        }
+
+        thread::sleep(retry);
+        retry = (retry * 2).min(RETRY_MAX);
+    }
+}
+
+/// Serve commands over a connection to the compositor until it is lost.
+fn session(
+    sender: &Sender,
+    active: &tokio::sync::watch::Sender<u32>,
+    a11y: &tokio::sync::watch::Sender<A11yState>,
+    available: &tokio::sync::watch::Sender<bool>,
+) -> anyhow::Result<()> {
+    let conn = Connection::connect_to_env()?;
+    let (globals, event_queue) = registry_queue_init(&conn)?;
     let qh: QueueHandle<AppData> = event_queue.handle();
     let registry_state = RegistryState::new(&globals);
     let seat_state = SeatState::new(&globals, &qh);
     let keyboard_layout_state = KeyboardLayoutState::new(&registry_state, &qh);
+    let toplevel_info_state = ToplevelInfoState::new(&registry_state, &qh);
+    let a11y_manager = globals
+        .bind::<CosmicA11yManagerV1, _, _>(&qh, 1..=2, ())
+        .inspect_err(|why| log::warn!("compositor accessibility protocol unavailable: {why}"))
+        .ok();
 
-    let mut event_loop = calloop::EventLoop::try_new().unwrap();
+    let mut event_loop = calloop::EventLoop::try_new()?;
     WaylandSource::new(conn, event_queue)
         .insert(event_loop.handle())
-        .unwrap();
+        .map_err(|why| why.error)?;
+
+    let (cmd_sender, channel) = calloop::channel::channel();
     event_loop
         .handle()
         .insert_source(channel, |event, _, app_data| match event {
             calloop::channel::Event::Msg(cmd) => match cmd {
                 Cmd::InputSourceSwitch => app_data.input_source_switch(),
+                Cmd::PreviousInputSource => app_data.previous_input_source(),
+                Cmd::SetInputSource(group) => app_data.set_input_source(group),
+                Cmd::SetInputSourceMemory(memory) => app_data.set_input_source_memory(memory),
             },
             calloop::channel::Event::Closed => {
                 app_data.running = false;
             }
         })
-        .unwrap();
+        .map_err(|why| why.error)?;
 
     let mut app_data = AppData {
         seat_state,
         registry_state,
         keyboard_layout_state,
+        toplevel_info_state,
         running: true,
         keyboard: None,
         current_layout: 0,
+        active: active.clone(),
+        memory: input_source_memory(),
+        focused: None,
+        remembered: HashMap::new(),
+        a11y_manager,
+        a11y: a11y.clone(),
+        filter: Filter::Disabled,
     };
+    app_data.apply_a11y();
+
+    *sender.0.lock().unwrap() = Some(cmd_sender);
+    available.send_replace(true);
+
     while app_data.running {
-        event_loop.dispatch(None, &mut app_data).unwrap();
+        event_loop.dispatch(None, &mut app_data)?;
     }
+
+    Ok(())
 }
 
 sctk::delegate_registry!(AppData);
 sctk::delegate_seat!(AppData);
 cctk::delegate_keyboard_layout!(AppData);
+cctk::delegate_toplevel_info!(AppData);
 delegate_noop!(AppData: ignore wl_keyboard::WlKeyboard);
+
+#[cfg(test)]
+mod tests {
+    use super::describe_layout;
+
+    const RULES: &str = "! layout
+  us              English (US)
+  de              German
+
+! variant
+  dvorak          us: English (Dvorak)
+  nodeadkeys      de: German (no dead keys)
+  us              de: German (US)
+";
+
+    #[test]
+    fn layout_descriptions() {
+        assert_eq!(
+            describe_layout(RULES, "us", "").as_deref(),
+            Some("English (US)")
+        );
+        assert_eq!(
+            describe_layout(RULES, "de", "nodeadkeys").as_deref(),
+            Some("German (no dead keys)")
+        );
+        assert_eq!(
+            describe_layout(RULES, "de", "us").as_deref(),
+            Some("German (US)")
+        );
+        assert_eq!(describe_layout(RULES, "fr", ""), None);
+        assert_eq!(describe_layout(RULES, "us", "nodeadkeys"), None);
+    }
+}
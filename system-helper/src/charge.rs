@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+const START_THRESHOLD: &str = "charge_control_start_threshold";
+const END_THRESHOLD: &str = "charge_control_end_threshold";
+
+/// Whether the thresholds are percentages with the start below the end.
+pub fn valid_thresholds(start: u8, end: u8) -> bool {
+    start < end && end <= 100
+}
+
+/// Batteries whose charging can be limited.
+fn batteries() -> io::Result<Vec<PathBuf>> {
+    let mut batteries = Vec::new();
+
+    for entry in fs::read_dir(POWER_SUPPLY)? {
+        let path = entry?.path();
+        let is_battery =
+            fs::read_to_string(path.join("type")).is_ok_and(|kind| kind.trim() == "Battery");
+
+        if is_battery && path.join(END_THRESHOLD).exists() {
+            batteries.push(path);
+        }
+    }
+
+    Ok(batteries)
+}
+
+/// Set the charge thresholds of every battery which supports them.
+pub fn set_thresholds(start: u8, end: u8) -> io::Result<()> {
+    let batteries = batteries()?;
+    if batteries.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no battery supports charge thresholds",
+        ));
+    }
+
+    for battery in batteries {
+        set_battery_thresholds(&battery, start, end)?;
+    }
+
+    Ok(())
+}
+
+fn set_battery_thresholds(battery: &Path, start: u8, end: u8) -> io::Result<()> {
+    let start_path = battery.join(START_THRESHOLD);
+    let end_path = battery.join(END_THRESHOLD);
+
+    // Some drivers reject a start above the current end, or an end below the current start, so
+    // the order of the writes depends on which way the thresholds move.
+    let current_end = fs::read_to_string(&end_path)?
+        .trim()
+        .parse::<u8>()
+        .unwrap_or(100);
+
+    if !start_path.exists() {
+        return fs::write(&end_path, end.to_string());
+    }
+
+    if start >= current_end {
+        fs::write(&end_path, end.to_string())?;
+        fs::write(&start_path, start.to_string())
+    } else {
+        fs::write(&start_path, start.to_string())?;
+        fs::write(&end_path, end.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_move_in_either_direction() {
+        let battery = std::env::temp_dir().join(format!("cosmic-charge-{}", std::process::id()));
+        fs::create_dir_all(&battery).unwrap();
+        fs::write(battery.join(START_THRESHOLD), "40").unwrap();
+        fs::write(battery.join(END_THRESHOLD), "60").unwrap();
+
+        set_battery_thresholds(&battery, 75, 80).unwrap();
+        assert_eq!(
+            fs::read_to_string(battery.join(START_THRESHOLD)).unwrap(),
+            "75"
+        );
+        assert_eq!(
+            fs::read_to_string(battery.join(END_THRESHOLD)).unwrap(),
+            "80"
+        );
+
+        set_battery_thresholds(&battery, 20, 50).unwrap();
+        assert_eq!(
+            fs::read_to_string(battery.join(START_THRESHOLD)).unwrap(),
+            "20"
+        );
+        assert_eq!(
+            fs::read_to_string(battery.join(END_THRESHOLD)).unwrap(),
+            "50"
+        );
+
+        fs::remove_dir_all(&battery).unwrap();
+        assert!(valid_thresholds(75, 80));
+        assert!(!valid_thresholds(80, 80));
+        assert!(!valid_thresholds(50, 101));
+    }
+}
@@ -0,0 +1,41 @@
+//! System bus helper which performs privileged operations for cosmic-settings-daemon.
+//!
+//! Every method is checked against a polkit action of its own, for the user who called it.
+
+pub const DBUS_NAME: &str = "com.system76.CosmicSettingsDaemon.Helper";
+pub const DBUS_PATH: &str = "/com/system76/CosmicSettingsDaemon/Helper";
+
+/// Polkit action for setting battery charge thresholds.
+pub const ACTION_SET_CHARGE_THRESHOLDS: &str =
+    "com.system76.CosmicSettingsDaemon.set-charge-thresholds";
+/// Polkit action for setting the system locale.
+pub const ACTION_SET_LOCALE: &str = "com.system76.CosmicSettingsDaemon.set-locale";
+/// Polkit action for setting the timezone, network time synchronization and hardware clock mode.
+pub const ACTION_SET_TIME: &str = "com.system76.CosmicSettingsDaemon.set-time";
+
+#[zbus::proxy(
+    interface = "com.system76.CosmicSettingsDaemon.Helper",
+    default_service = "com.system76.CosmicSettingsDaemon.Helper",
+    default_path = "/com/system76/CosmicSettingsDaemon/Helper"
+)]
+pub trait Helper {
+    /// Start charging batteries below `start` percent, and stop charging them at `end` percent.
+    #[zbus(allow_interactive_auth)]
+    fn set_charge_thresholds(&self, start: u8, end: u8) -> zbus::Result<()>;
+
+    /// Set the system locale from `VARIABLE=value` assignments.
+    #[zbus(allow_interactive_auth)]
+    fn set_locale(&self, locale: &[&str]) -> zbus::Result<()>;
+
+    /// Set the system timezone, such as `Europe/Oslo`.
+    #[zbus(allow_interactive_auth)]
+    fn set_timezone(&self, timezone: &str) -> zbus::Result<()>;
+
+    /// Enable or disable network time synchronization.
+    #[zbus(name = "SetNTP", allow_interactive_auth)]
+    fn set_ntp(&self, enabled: bool) -> zbus::Result<()>;
+
+    /// Keep the hardware clock in local time instead of UTC.
+    #[zbus(name = "SetLocalRTC", allow_interactive_auth)]
+    fn set_local_rtc(&self, local: bool) -> zbus::Result<()>;
+}
@@ -0,0 +1,208 @@
+use cosmic_settings_system_helper::{
+    ACTION_SET_CHARGE_THRESHOLDS, ACTION_SET_LOCALE, ACTION_SET_TIME, DBUS_NAME, DBUS_PATH,
+};
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::signal::unix::SignalKind;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use zbus::message::Header;
+
+mod charge;
+mod polkit;
+
+/// How long the helper waits for another request before exiting. The bus starts it again for
+/// the next one.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[zbus::proxy(
+    interface = "org.freedesktop.locale1",
+    default_service = "org.freedesktop.locale1",
+    default_path = "/org/freedesktop/locale1"
+)]
+trait Locale1 {
+    fn set_locale(&self, locale: &[&str], interactive: bool) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.timedate1",
+    default_service = "org.freedesktop.timedate1",
+    default_path = "/org/freedesktop/timedate1"
+)]
+trait Timedate1 {
+    fn set_timezone(&self, timezone: &str, interactive: bool) -> zbus::Result<()>;
+
+    #[zbus(name = "SetNTP")]
+    fn set_ntp(&self, use_ntp: bool, interactive: bool) -> zbus::Result<()>;
+
+    #[zbus(name = "SetLocalRTC")]
+    fn set_local_rtc(
+        &self,
+        local_rtc: bool,
+        fix_system: bool,
+        interactive: bool,
+    ) -> zbus::Result<()>;
+}
+
+struct Helper {
+    /// Number of requests being handled, which keeps the helper from exiting while one waits for
+    /// authentication.
+    requests: tokio::sync::watch::Sender<usize>,
+}
+
+impl Helper {
+    fn request(&self) -> Request<'_> {
+        self.requests.send_modify(|count| *count += 1);
+        Request(&self.requests)
+    }
+}
+
+struct Request<'a>(&'a tokio::sync::watch::Sender<usize>);
+
+impl Drop for Request<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+#[zbus::interface(name = "com.system76.CosmicSettingsDaemon.Helper")]
+impl Helper {
+    async fn set_charge_thresholds(
+        &self,
+        start: u8,
+        end: u8,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let _request = self.request();
+        if !charge::valid_thresholds(start, end) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "invalid charge thresholds {start}-{end}"
+            )));
+        }
+
+        polkit::check(connection, &header, ACTION_SET_CHARGE_THRESHOLDS).await?;
+        charge::set_thresholds(start, end).map_err(|why| {
+            zbus::fdo::Error::Failed(format!("failed to set charge thresholds: {why}"))
+        })
+    }
+
+    async fn set_locale(
+        &self,
+        locale: Vec<String>,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let _request = self.request();
+        polkit::check(connection, &header, ACTION_SET_LOCALE).await?;
+        let locale: Vec<&str> = locale.iter().map(String::as_str).collect();
+        Locale1Proxy::new(connection)
+            .await?
+            .set_locale(&locale, false)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_timezone(
+        &self,
+        timezone: &str,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let _request = self.request();
+        polkit::check(connection, &header, ACTION_SET_TIME).await?;
+        Timedate1Proxy::new(connection)
+            .await?
+            .set_timezone(timezone, false)
+            .await?;
+        Ok(())
+    }
+
+    #[zbus(name = "SetNTP")]
+    async fn set_ntp(
+        &self,
+        enabled: bool,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let _request = self.request();
+        polkit::check(connection, &header, ACTION_SET_TIME).await?;
+        Timedate1Proxy::new(connection)
+            .await?
+            .set_ntp(enabled, false)
+            .await?;
+        Ok(())
+    }
+
+    #[zbus(name = "SetLocalRTC")]
+    async fn set_local_rtc(
+        &self,
+        local: bool,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let _request = self.request();
+        polkit::check(connection, &header, ACTION_SET_TIME).await?;
+        Timedate1Proxy::new(connection)
+            .await?
+            .set_local_rtc(local, false, false)
+            .await?;
+        Ok(())
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_env("RUST_LOG"))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+
+    let (requests, mut requests_rx) = tokio::sync::watch::channel(0);
+    let connection = async {
+        zbus::connection::Builder::system()?
+            .name(DBUS_NAME)?
+            .serve_at(DBUS_PATH, Helper { requests })?
+            .build()
+            .await
+    }
+    .await;
+
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::error!("Failed to serve {DBUS_NAME}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Ok(mut sigterm) = tokio::signal::unix::signal(SignalKind::terminate()) else {
+        return ExitCode::FAILURE;
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => (),
+        _ = idle(&mut requests_rx) => {
+            // Stop taking requests before exiting, so the bus starts a new helper for them.
+            _ = connection.release_name(DBUS_NAME).await;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Completes once no request has been handled for `IDLE_TIMEOUT`.
+async fn idle(requests: &mut tokio::sync::watch::Receiver<usize>) {
+    loop {
+        if requests.wait_for(|count| *count == 0).await.is_err() {
+            return;
+        }
+
+        if tokio::time::timeout(IDLE_TIMEOUT, requests.changed())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
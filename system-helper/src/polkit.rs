@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use zbus::message::{Flags, Header};
+use zbus::zvariant::Value;
+
+/// Let polkit ask the user to authenticate.
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait Authority {
+    fn check_authorization(
+        &self,
+        subject: &(&str, HashMap<&str, Value<'_>>),
+        action_id: &str,
+        details: &HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<(bool, bool, HashMap<String, String>)>;
+}
+
+/// Check that the sender of a method call is authorized for `action_id`.
+///
+/// Polkit may ask the user to authenticate if the caller allowed interactive authorization.
+pub async fn check(
+    connection: &zbus::Connection,
+    header: &Header<'_>,
+    action_id: &str,
+) -> zbus::fdo::Result<()> {
+    let Some(sender) = header.sender() else {
+        return Err(zbus::fdo::Error::AccessDenied(
+            "the caller has no bus name".to_owned(),
+        ));
+    };
+
+    let flags = if header
+        .primary()
+        .flags()
+        .contains(Flags::AllowInteractiveAuth)
+    {
+        ALLOW_USER_INTERACTION
+    } else {
+        0
+    };
+
+    let subject = (
+        "system-bus-name",
+        HashMap::from([("name", Value::from(sender.as_str()))]),
+    );
+
+    let authority = AuthorityProxy::new(connection).await?;
+    let (authorized, _challenge, _details) = authority
+        .check_authorization(&subject, action_id, &HashMap::new(), flags, "")
+        .await?;
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(zbus::fdo::Error::AccessDenied(format!(
+            "not authorized for {action_id}"
+        )))
+    }
+}
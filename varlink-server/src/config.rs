@@ -0,0 +1,430 @@
+// Copyright 2026 System76 <info@system76.com>
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Raw access to cosmic-config keys as the RON text stored on disk, for clients that cannot
+//! read the files themselves, and change events for clients watching them.
+
+use cosmic_settings_audio_server::EventCodec;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::unix::pipe;
+use tokio::sync::Mutex;
+use tokio_util::codec::FramedWrite;
+use zlink::{ReplyError, introspect};
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, PartialEq, ReplyError, introspect::ReplyError)]
+#[zlink(interface = "com.system76.CosmicConfig")]
+pub enum Error {
+    InvalidName { name: String },
+    NoSuchKey { key: String },
+    InvalidValue { key: String, why: String },
+    NoHomeDir,
+    IO { code: Option<i32>, why: String },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidName { name } => write!(f, "invalid name {name:?}"),
+            Error::NoSuchKey { key } => write!(f, "no key {key}"),
+            Error::InvalidValue { key, why } => write!(f, "invalid RON value for {key}: {why}"),
+            Error::NoHomeDir => f.write_str("no home directory"),
+            Error::IO { code, why } => write!(f, "I/O error (code {code:?}): {why}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(why: io::Error) -> Self {
+        Error::IO {
+            code: why.raw_os_error(),
+            why: format!("{}", why),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Config,
+    State,
+}
+
+/// Reply carrying the RON value of a key.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, introspect::Type)]
+pub struct Value {
+    pub value: String,
+}
+
+/// Sent over a watch pipe, RON-serialized and framed by `EventCodec`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Event {
+    /// A key's RON value, sent for every key when the watch starts and on each change. The value
+    /// is empty when the key was removed and has no system default.
+    Changed { key: String, value: String },
+}
+
+/// Where values are stored: the user's config and state directories, and the data directories
+/// holding system defaults.
+#[derive(Debug, Clone)]
+pub struct Store {
+    config: Option<PathBuf>,
+    state: Option<PathBuf>,
+    data_dirs: Vec<PathBuf>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        let data_dirs = std::env::var("XDG_DATA_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+
+        Self {
+            config: dirs::config_dir()
+                .map(|x| x.join("cosmic"))
+                .or_else(|| dirs::home_dir().map(|p| p.join(".config/cosmic"))),
+            state: dirs::state_dir()
+                .map(|x| x.join("cosmic"))
+                .or_else(|| dirs::home_dir().map(|p| p.join(".local/state/cosmic"))),
+            data_dirs: data_dirs
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| Path::new(dir).join("cosmic"))
+                .collect(),
+        }
+    }
+}
+
+impl Store {
+    /// Directory holding the user's values, where writes go.
+    fn user_dir(&self, kind: Kind, id: &str, version: u64) -> Result<PathBuf, Error> {
+        validate_component(id)?;
+        let root = match kind {
+            Kind::Config => self.config.as_ref(),
+            Kind::State => self.state.as_ref(),
+        };
+        root.map(|root| root.join(id).join(format!("v{version}")))
+            .ok_or(Error::NoHomeDir)
+    }
+
+    /// Directories holding system defaults, in order of precedence. State has no defaults.
+    fn system_dirs(&self, kind: Kind, id: &str, version: u64) -> Vec<PathBuf> {
+        if kind == Kind::State {
+            return Vec::new();
+        }
+        self.data_dirs
+            .iter()
+            .map(|dir| dir.join(id).join(format!("v{version}")))
+            .collect()
+    }
+
+    /// The RON value of `key`, falling back to the system default.
+    pub fn get(&self, kind: Kind, id: &str, version: u64, key: &str) -> Result<String, Error> {
+        validate_component(key)?;
+        let user = self.user_dir(kind, id, version)?;
+        for dir in std::iter::once(user).chain(self.system_dirs(kind, id, version)) {
+            if let Some(value) = read_key(&dir, key)? {
+                return Ok(value);
+            }
+        }
+        Err(Error::NoSuchKey {
+            key: key.to_owned(),
+        })
+    }
+
+    /// Writes `value` to the user's `key` after checking that it is valid RON.
+    pub fn set(
+        &self,
+        kind: Kind,
+        id: &str,
+        version: u64,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        validate_component(key)?;
+        if let Err(why) = ron::from_str::<ron::Value>(value) {
+            return Err(Error::InvalidValue {
+                key: key.to_owned(),
+                why: why.to_string(),
+            });
+        }
+        let dir = self.user_dir(kind, id, version)?;
+        std::fs::create_dir_all(&dir)?;
+
+        // Write next to the config tree and rename, so readers never see a partial value. The
+        // temporary file is outside any `id/version` directory, so the watcher ignores it.
+        let root = dir.parent().and_then(Path::parent).unwrap_or(dir.as_path());
+        let tmp = root.join(format!(
+            ".atomicwrite-daemon-{}",
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&tmp, value)
+            .and_then(|()| std::fs::rename(&tmp, dir.join(key)))
+            .map_err(|why| {
+                let _ = std::fs::remove_file(&tmp);
+                why.into()
+            })
+    }
+
+    /// Keys with a user value or a system default.
+    pub fn list(&self, kind: Kind, id: &str, version: u64) -> Result<Vec<String>, Error> {
+        let mut keys = BTreeSet::new();
+        list_dir(&self.user_dir(kind, id, version)?, &mut keys)?;
+        for dir in self.system_dirs(kind, id, version) {
+            list_dir(&dir, &mut keys)?;
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    pub fn get_all(
+        &self,
+        kind: Kind,
+        id: &str,
+        version: u64,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        self.list(kind, id, version)?
+            .into_iter()
+            .map(|key| {
+                let value = self.get(kind, id, version, &key)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+/// Ids and keys become path components, so they must not escape the config directory.
+fn validate_component(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\0']) {
+        return Err(Error::InvalidName {
+            name: name.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+fn read_key(dir: &Path, key: &str) -> Result<Option<String>, Error> {
+    match std::fs::read_to_string(dir.join(key)) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn list_dir(dir: &Path, keys: &mut BTreeSet<String>) -> Result<(), Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries.flatten() {
+        if !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        if let Some(key) = entry.file_name().to_str()
+            && validate_component(key).is_ok()
+        {
+            keys.insert(key.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// The RON value of `key` in the user's store, falling back to the system default.
+pub fn get(kind: Kind, id: &str, version: u64, key: &str) -> Result<String, Error> {
+    Store::default().get(kind, id, version, key)
+}
+
+/// Writes `value` to `key` in the user's store after checking that it is valid RON.
+pub fn set(kind: Kind, id: &str, version: u64, key: &str, value: &str) -> Result<(), Error> {
+    Store::default().set(kind, id, version, key, value)
+}
+
+/// Keys with a user value or a system default.
+pub fn list(kind: Kind, id: &str, version: u64) -> Result<Vec<String>, Error> {
+    Store::default().list(kind, id, version)
+}
+
+pub fn get_all(kind: Kind, id: &str, version: u64) -> Result<BTreeMap<String, String>, Error> {
+    Store::default().get_all(kind, id, version)
+}
+
+type Subscribers = Arc<Mutex<Vec<pipe::Sender>>>;
+
+/// Pipes of clients watching each config or state id.
+#[derive(Default)]
+pub struct Watchers {
+    store: Store,
+    subscribers: HashMap<(Kind, String, u64), Subscribers>,
+}
+
+impl Watchers {
+    /// Request a non-blocking anonymous pipe receiving the current value of every key of the
+    /// id, followed by each change.
+    pub fn watch(&mut self, kind: Kind, id: &str, version: u64) -> Result<OwnedFd, Error> {
+        validate_component(id)?;
+        let (writer, reader) = pipe::pipe()?;
+        let reader = reader.into_nonblocking_fd()?;
+
+        let subscribers = self
+            .subscribers
+            .entry((kind, id.to_owned(), version))
+            .or_default()
+            .clone();
+        let id = id.to_owned();
+        let store = self.store.clone();
+
+        // Emit current values to the new client before adding it to the subscribers.
+        tokio::task::spawn(async move {
+            let mut subscribers = subscribers.lock().await;
+            let mut writer = FramedWrite::new(writer, EventCodec);
+            let values = store.get_all(kind, &id, version).unwrap_or_default();
+            for (key, value) in values {
+                let Ok(serialized) = ron::ser::to_string(&Event::Changed { key, value }) else {
+                    continue;
+                };
+                if writer.send(serialized.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            subscribers.push(writer.into_inner());
+        });
+
+        Ok(reader)
+    }
+
+    /// Send the new value of `key` to the clients watching its id.
+    pub fn changed(&mut self, kind: Kind, id: &str, version: u64, key: &str) {
+        // Forget ids whose clients have all closed their pipes. A watch whose current values
+        // are still being sent holds another reference until it joins the subscribers.
+        self.subscribers.retain(|_, subscribers| {
+            Arc::strong_count(subscribers) > 1
+                || !subscribers.try_lock().is_ok_and(|s| s.is_empty())
+        });
+
+        let Some(subscribers) = self.subscribers.get(&(kind, id.to_owned(), version)) else {
+            return;
+        };
+        let value = match self.store.get(kind, id, version, key) {
+            Ok(value) => value,
+            Err(Error::NoSuchKey { .. }) => String::new(),
+            Err(_) => return,
+        };
+        let event = Event::Changed {
+            key: key.to_owned(),
+            value,
+        };
+        if let Ok(serialized) = ron::ser::to_string(&event) {
+            tokio::task::spawn(emit_serialized(subscribers.clone(), serialized));
+        }
+    }
+}
+
+async fn emit_serialized(subscribers: Subscribers, serialized: String) {
+    let mut subscribers_guard = subscribers.lock().await;
+    let subscribers: Vec<pipe::Sender> = std::mem::take(&mut subscribers_guard);
+    *subscribers_guard = subscribers
+        .into_iter()
+        .map(move |subscriber| {
+            let serialized = serialized.clone();
+            async move {
+                let mut writer = FramedWrite::new(subscriber, EventCodec);
+                if writer.send(serialized.as_bytes()).await.is_ok() {
+                    Some(writer.into_inner())
+                } else {
+                    None
+                }
+            }
+        })
+        .collect::<futures_util::stream::FuturesUnordered<_>>()
+        .fold(Vec::new(), |mut retained, result| async move {
+            if let Some(subscriber) = result {
+                retained.push(subscriber);
+            }
+            retained
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// Read one `EventCodec` frame.
+    async fn read_event(reader: &mut pipe::Receiver) -> Event {
+        let read = async {
+            let mut length = [0; 4];
+            reader.read_exact(&mut length).await?;
+            let mut frame = vec![0; u32::from_le_bytes(length) as usize];
+            reader.read_exact(&mut frame).await?;
+            Ok::<_, io::Error>(frame)
+        };
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), read)
+            .await
+            .expect("no event within 5 seconds")
+            .unwrap();
+        ron::from_str(std::str::from_utf8(&frame).unwrap()).unwrap()
+    }
+
+    fn changed(key: &str, value: &str) -> Event {
+        Event::Changed {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn watchers_receive_current_values_then_changes() {
+        let root = std::env::temp_dir().join(format!("cosmic-config-{}", std::process::id()));
+        let store = Store {
+            config: Some(root.join("config")),
+            state: Some(root.join("state")),
+            data_dirs: vec![root.join("data")],
+        };
+        let id = "com.system76.Example";
+        store.set(Kind::Config, id, 1, "a", "1").unwrap();
+
+        let mut watchers = Watchers {
+            store: store.clone(),
+            subscribers: HashMap::new(),
+        };
+        let fd = watchers.watch(Kind::Config, id, 1).unwrap();
+        let mut reader = pipe::Receiver::from_owned_fd(fd).unwrap();
+
+        // A change to another id, before the new watch has joined the subscribers, must not
+        // forget it.
+        watchers.changed(Kind::Config, "com.system76.Other", 1, "a");
+
+        assert_eq!(read_event(&mut reader).await, changed("a", "1"));
+
+        store.set(Kind::Config, id, 1, "b", "(x: 2)").unwrap();
+        watchers.changed(Kind::Config, id, 1, "b");
+        assert_eq!(read_event(&mut reader).await, changed("b", "(x: 2)"));
+
+        std::fs::remove_file(root.join("config").join(id).join("v1").join("b")).unwrap();
+        watchers.changed(Kind::Config, id, 1, "b");
+        assert_eq!(read_event(&mut reader).await, changed("b", ""));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn names_cannot_escape_the_config_dir() {
+        assert!(validate_component("com.system76.CosmicComp").is_ok());
+        assert!(validate_component("xkb_config").is_ok());
+        assert!(validate_component("").is_err());
+        assert!(validate_component("..").is_err());
+        assert!(validate_component(".atomicwrite").is_err());
+        assert!(validate_component("a/../../etc").is_err());
+    }
+}
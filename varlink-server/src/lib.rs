@@ -4,10 +4,11 @@
 //! Varlink frontend for cosmic-settings-daemon
 
 // TODO:
-// - com.system76.CosmicConfig config, set_config, watch_config, state, set_state, watch_state,
 // - com.system76.CosmicSettings.Display increase_brightness, decrease_brightness, set_brightness, recv_brightness,
 // - com.system76.CosmicSettings.Keyboard increase_brightness, decrease_brightness, set_brightness, recv_brightness,
 
+pub mod config;
+
 use cosmic_settings_audio_core as audio;
 use cosmic_settings_audio_server as audio_server;
 use std::os::fd::OwnedFd;
@@ -20,11 +21,16 @@ pub async fn init() -> (Daemon, impl Future<Output = ()> + 'static + Send) {
 
     let daemon = Daemon(Arc::new(Mutex::new(DaemonInner {
         audio_server: audio_server::Server::new(audio_ctx.clone()).await,
+        config_watchers: config::Watchers::default(),
     })));
 
     (daemon, audio_ctx.run(audio_ctx_rx))
 }
 
+/// The socket lives in the user's runtime dir, which Flatpak apps only see the entries of that
+/// they were granted. Unlike the D-Bus `Config` interface, the config methods therefore do not
+/// confine sandboxed clients to their own ids: an app can only reach the socket if it was given
+/// `--filesystem=xdg-run/com.system76.CosmicSettings`, which trusts it with every config entry.
 fn socket_path() -> PathBuf {
     dirs::runtime_dir()
         .expect("runtime dir required by varlink service")
@@ -44,6 +50,25 @@ impl Daemon {
             tracing::error!("zlink service failed: {}", why);
         }
     }
+
+    async fn config_watch(
+        &mut self,
+        kind: config::Kind,
+        id: &str,
+        version: u64,
+    ) -> (Result<(), config::Error>, Vec<OwnedFd>) {
+        let mut fds = Vec::new();
+        let mut this = self.0.lock().await;
+        let reply = match this.config_watchers.watch(kind, id, version) {
+            Ok(fd) => {
+                fds.push(fd);
+                Ok(())
+            }
+            Err(why) => Err(why),
+        };
+
+        (reply, fds)
+    }
 }
 
 #[zlink::service(interface = "com.system76.CosmicSettings")]
@@ -51,6 +76,74 @@ impl<Sock> Daemon
 where
     Sock::ReadHalf: zlink::connection::socket::FetchPeerCredentials,
 {
+    #[zlink(interface = "com.system76.CosmicConfig", rename = "Config")]
+    pub async fn config(
+        &mut self,
+        id: String,
+        version: u64,
+        key: String,
+    ) -> Result<config::Value, config::Error> {
+        config::get(config::Kind::Config, &id, version, &key).map(|value| config::Value { value })
+    }
+
+    #[zlink(interface = "com.system76.CosmicConfig", rename = "SetConfig")]
+    pub async fn set_config(
+        &mut self,
+        id: String,
+        version: u64,
+        key: String,
+        value: String,
+    ) -> Result<(), config::Error> {
+        config::set(config::Kind::Config, &id, version, &key, &value)
+    }
+
+    #[zlink(
+        interface = "com.system76.CosmicConfig",
+        rename = "WatchConfig",
+        return_fds
+    )]
+    pub async fn watch_config(
+        &mut self,
+        id: String,
+        version: u64,
+    ) -> (Result<(), config::Error>, Vec<OwnedFd>) {
+        self.config_watch(config::Kind::Config, &id, version).await
+    }
+
+    #[zlink(interface = "com.system76.CosmicConfig", rename = "State")]
+    pub async fn state(
+        &mut self,
+        id: String,
+        version: u64,
+        key: String,
+    ) -> Result<config::Value, config::Error> {
+        config::get(config::Kind::State, &id, version, &key).map(|value| config::Value { value })
+    }
+
+    #[zlink(interface = "com.system76.CosmicConfig", rename = "SetState")]
+    pub async fn set_state(
+        &mut self,
+        id: String,
+        version: u64,
+        key: String,
+        value: String,
+    ) -> Result<(), config::Error> {
+        config::set(config::Kind::State, &id, version, &key, &value)
+    }
+
+    #[zlink(
+        interface = "com.system76.CosmicConfig",
+        rename = "WatchState",
+        return_fds
+    )]
+    pub async fn watch_state(
+        &mut self,
+        id: String,
+        version: u64,
+    ) -> (Result<(), config::Error>, Vec<OwnedFd>) {
+        self.config_watch(config::Kind::State, &id, version).await
+    }
+
     #[zlink(
         interface = "com.system76.CosmicSettings.Audio",
         rename = "RecvEvents",
@@ -345,4 +438,6 @@ where
 
 pub struct DaemonInner {
     pub audio_server: audio_server::Server,
+    /// Clients watching cosmic-config and state ids, notified by the daemon's file watcher.
+    pub config_watchers: config::Watchers,
 }